@@ -0,0 +1,949 @@
+use std::fs;
+use std::result;
+
+use bigdecimal::BigDecimal;
+
+use crate::instruction::{is_exit_code, Instruction};
+use crate::program::{Data, LineTable, Program};
+use crate::value::{plain_digits, Type, Value, MAX_LITERAL_DIGITS};
+
+// Layout of a compiled program (all integers are little-endian):
+//
+//   header:  magic "AVMC" | version: u16 | flags: u16 | payload length: u32 | payload crc32: u32
+//   payload: constant pool | code | data section (only if FLAG_DATA is set)
+//            | debug section (only if FLAG_DEBUG is set)
+//
+//   constant pool: count: u32, then for each bigdecimal constant: length: u32, decimal string
+//   code:          count: u32, then for each instruction: opcode: u8, then its operands if any
+//                  (values are tagged, strings are length: u32 then UTF-8 bytes, optional
+//                  operands are preceded by a presence byte)
+//   data section:  count: u32, then for each block: count: u32, then its tagged values
+//   debug section: one source line number: u32 per instruction
+
+pub const EXTENSION: &str = "avmc";
+
+const MAGIC: &[u8; 4] = b"AVMC";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 16;
+
+const FLAG_DEBUG: u16 = 0x0001;
+const FLAG_DATA: u16 = 0x0002;
+
+// Opcodes
+const OP_NOOP: u8 = 0x00;
+const OP_PUSH: u8 = 0x01;
+const OP_POP: u8 = 0x02;
+const OP_DUMP: u8 = 0x03;
+const OP_CLEAR: u8 = 0x04;
+const OP_DUP: u8 = 0x05;
+const OP_SWAP: u8 = 0x06;
+const OP_ASSERT: u8 = 0x07;
+const OP_ASSERT_EQ: u8 = 0x08;
+const OP_ASSERT_NE: u8 = 0x09;
+const OP_ASSERT_LT: u8 = 0x0a;
+const OP_ASSERT_TYPE: u8 = 0x0b;
+const OP_ASSERT_DEPTH: u8 = 0x0c;
+const OP_ASSERT_APPROX: u8 = 0x0d;
+const OP_ASSERT_ULPS: u8 = 0x0e;
+const OP_ADD: u8 = 0x10;
+const OP_SUB: u8 = 0x11;
+const OP_MUL: u8 = 0x12;
+const OP_DIV: u8 = 0x13;
+const OP_MOD: u8 = 0x14;
+const OP_LOAD: u8 = 0x20;
+const OP_STORE: u8 = 0x21;
+const OP_ISSET: u8 = 0x22;
+const OP_REGCLEAR: u8 = 0x23;
+const OP_PRINT: u8 = 0x30;
+const OP_PRINTS: u8 = 0x31;
+const OP_PRINTV: u8 = 0x32;
+const OP_PRINTD: u8 = 0x33;
+const OP_PUTC: u8 = 0x34;
+const OP_PRINTF: u8 = 0x35;
+const OP_CONCAT: u8 = 0x50;
+const OP_LEN: u8 = 0x51;
+const OP_CHARAT: u8 = 0x52;
+const OP_TOSTR: u8 = 0x53;
+const OP_PARSE: u8 = 0x54;
+const OP_EXIT: u8 = 0x40;
+const OP_ALLOC: u8 = 0x60;
+const OP_HLOAD: u8 = 0x61;
+const OP_HSTORE: u8 = 0x62;
+const OP_FREE: u8 = 0x63;
+const OP_ADDR: u8 = 0x64;
+const OP_AGET: u8 = 0x70;
+const OP_ASET: u8 = 0x71;
+const OP_ALEN: u8 = 0x72;
+const OP_APUSH: u8 = 0x73;
+const OP_EQ: u8 = 0x80;
+const OP_NE: u8 = 0x81;
+const OP_LT: u8 = 0x82;
+const OP_LE: u8 = 0x83;
+const OP_GT: u8 = 0x84;
+const OP_GE: u8 = 0x85;
+const OP_CAST: u8 = 0x86;
+const OP_OVER: u8 = 0x90;
+const OP_ROT: u8 = 0x91;
+const OP_MINUS_ROT: u8 = 0x92;
+const OP_NIP: u8 = 0x93;
+const OP_TUCK: u8 = 0x94;
+const OP_PICK: u8 = 0x95;
+const OP_ROLL: u8 = 0x96;
+const OP_DROP: u8 = 0x97;
+const OP_DEPTH: u8 = 0x98;
+const OP_TRY: u8 = 0xa0;
+const OP_ENDTRY: u8 = 0xa1;
+const OP_THROW: u8 = 0xa2;
+const OP_CALL: u8 = 0xb0;
+const OP_RET: u8 = 0xb1;
+const OP_ENTER: u8 = 0xb2;
+const OP_LEAVE: u8 = 0xb3;
+const OP_LLOAD: u8 = 0xb4;
+const OP_LSTORE: u8 = 0xb5;
+const OP_NATIVE: u8 = 0xc0;
+
+// Value tags
+const TAG_INT8: u8 = 0x00;
+const TAG_INT16: u8 = 0x01;
+const TAG_INT32: u8 = 0x02;
+const TAG_FLOAT: u8 = 0x03;
+const TAG_DOUBLE: u8 = 0x04;
+const TAG_BIGDECIMAL: u8 = 0x05;
+const TAG_STRING: u8 = 0x06;
+const TAG_ARRAY: u8 = 0x07;
+const TAG_TUPLE: u8 = 0x08;
+const TAG_BOOL: u8 = 0x09;
+const TAG_CHAR: u8 = 0x0a;
+
+/////////////////////////////
+// Encoding
+/////////////////////////////
+
+#[derive(Default)]
+struct Encoder {
+    pool: Vec<String>,
+    code: Vec<u8>,
+    overflow: bool, // whether an operand was too large for its encoding
+}
+
+impl Encoder {
+    fn pool_index(&mut self, v: &BigDecimal) -> u32 {
+        let s = v.to_string();
+        match self.pool.iter().position(|p| *p == s) {
+            Some(i) => i as u32,
+            None => {
+                self.pool.push(s);
+                (self.pool.len() - 1) as u32
+            }
+        }
+    }
+
+    fn u32(&mut self, v: usize) {
+        match u32::try_from(v) {
+            Ok(v) => self.code.extend_from_slice(&v.to_le_bytes()),
+            Err(_) => self.overflow = true,
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len());
+        self.code.extend_from_slice(s.as_bytes());
+    }
+
+    fn message(&mut self, m: &Option<String>) {
+        match m {
+            None => self.code.push(0),
+            Some(m) => {
+                self.code.push(1);
+                self.string(m);
+            }
+        }
+    }
+
+    fn value_type(&mut self, t: &Type) {
+        self.code.push(match t {
+            Type::Int8 => TAG_INT8,
+            Type::Int16 => TAG_INT16,
+            Type::Int32 => TAG_INT32,
+            Type::Float => TAG_FLOAT,
+            Type::Double => TAG_DOUBLE,
+            Type::BigDecimal => TAG_BIGDECIMAL,
+            Type::String => TAG_STRING,
+            Type::Array => TAG_ARRAY,
+            Type::Tuple => TAG_TUPLE,
+            Type::Bool => TAG_BOOL,
+            Type::Char => TAG_CHAR,
+        });
+    }
+
+    fn value(&mut self, v: &Value) {
+        match v {
+            Value::Int8(v) => {
+                self.code.push(TAG_INT8);
+                self.code.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Int16(v) => {
+                self.code.push(TAG_INT16);
+                self.code.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Int32(v) => {
+                self.code.push(TAG_INT32);
+                self.code.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Float(v) => {
+                self.code.push(TAG_FLOAT);
+                self.code.extend_from_slice(&v.to_bits().to_le_bytes());
+            }
+            Value::Double(v) => {
+                self.code.push(TAG_DOUBLE);
+                self.code.extend_from_slice(&v.to_bits().to_le_bytes());
+            }
+            Value::BigDecimal(v) => {
+                let index = self.pool_index(v);
+                self.code.push(TAG_BIGDECIMAL);
+                self.code.extend_from_slice(&index.to_le_bytes());
+            }
+            Value::String(v) => {
+                self.code.push(TAG_STRING);
+                self.string(v);
+            }
+            Value::Bool(v) => {
+                self.code.push(TAG_BOOL);
+                self.code.push(*v as u8);
+            }
+            Value::Char(v) => {
+                self.code.push(TAG_CHAR);
+                self.u32(*v as usize);
+            }
+            Value::Array(elements) | Value::Tuple(elements) => {
+                self.value_type(&v.get_type());
+                self.u32(elements.len());
+                for element in elements {
+                    self.value(element);
+                }
+            }
+        }
+    }
+
+    fn instruction(&mut self, i: &Instruction) {
+        match i {
+            Instruction::Noop => self.code.push(OP_NOOP),
+            Instruction::Push(v) => {
+                self.code.push(OP_PUSH);
+                self.value(v);
+            }
+            Instruction::Pop => self.code.push(OP_POP),
+            Instruction::Dump => self.code.push(OP_DUMP),
+            Instruction::Clear => self.code.push(OP_CLEAR),
+            Instruction::Dup => self.code.push(OP_DUP),
+            Instruction::Swap => self.code.push(OP_SWAP),
+            Instruction::Over => self.code.push(OP_OVER),
+            Instruction::Rot => self.code.push(OP_ROT),
+            Instruction::MinusRot => self.code.push(OP_MINUS_ROT),
+            Instruction::Nip => self.code.push(OP_NIP),
+            Instruction::Tuck => self.code.push(OP_TUCK),
+            Instruction::Pick(n) => {
+                self.code.push(OP_PICK);
+                self.u32(*n);
+            }
+            Instruction::Roll(n) => {
+                self.code.push(OP_ROLL);
+                self.u32(*n);
+            }
+            Instruction::Drop(n) => {
+                self.code.push(OP_DROP);
+                self.u32(*n);
+            }
+            Instruction::Depth => self.code.push(OP_DEPTH),
+            Instruction::Assert(v) => {
+                self.code.push(OP_ASSERT);
+                self.value(v);
+            }
+            Instruction::AssertEq(m) => {
+                self.code.push(OP_ASSERT_EQ);
+                self.message(m);
+            }
+            Instruction::AssertNe(m) => {
+                self.code.push(OP_ASSERT_NE);
+                self.message(m);
+            }
+            Instruction::AssertLt(m) => {
+                self.code.push(OP_ASSERT_LT);
+                self.message(m);
+            }
+            Instruction::AssertType(t, m) => {
+                self.code.push(OP_ASSERT_TYPE);
+                self.value_type(t);
+                self.message(m);
+            }
+            Instruction::AssertDepth(n, m) => {
+                self.code.push(OP_ASSERT_DEPTH);
+                self.u32(*n);
+                self.message(m);
+            }
+            Instruction::AssertApprox(v, e, m) => {
+                self.code.push(OP_ASSERT_APPROX);
+                self.value(v);
+                self.value(e);
+                self.message(m);
+            }
+            Instruction::AssertUlps(v, n, m) => {
+                self.code.push(OP_ASSERT_ULPS);
+                self.value(v);
+                self.u32(*n as usize);
+                self.message(m);
+            }
+            Instruction::Add => self.code.push(OP_ADD),
+            Instruction::Sub => self.code.push(OP_SUB),
+            Instruction::Mul => self.code.push(OP_MUL),
+            Instruction::Div => self.code.push(OP_DIV),
+            Instruction::Mod => self.code.push(OP_MOD),
+            Instruction::Load(r) => {
+                self.code.push(OP_LOAD);
+                self.code.push(*r as u8);
+            }
+            Instruction::Store(r) => {
+                self.code.push(OP_STORE);
+                self.code.push(*r as u8);
+            }
+            Instruction::IsSet(r) => {
+                self.code.push(OP_ISSET);
+                self.code.push(*r as u8);
+            }
+            Instruction::RegClear => self.code.push(OP_REGCLEAR),
+            Instruction::Print => self.code.push(OP_PRINT),
+            Instruction::Prints => self.code.push(OP_PRINTS),
+            Instruction::Printv => self.code.push(OP_PRINTV),
+            Instruction::Printd => self.code.push(OP_PRINTD),
+            Instruction::Putc => self.code.push(OP_PUTC),
+            Instruction::Printf(fmt, n) => {
+                self.code.push(OP_PRINTF);
+                self.string(fmt);
+                self.u32(*n);
+            }
+            Instruction::Concat => self.code.push(OP_CONCAT),
+            Instruction::Len => self.code.push(OP_LEN),
+            Instruction::CharAt => self.code.push(OP_CHARAT),
+            Instruction::ToStr => self.code.push(OP_TOSTR),
+            Instruction::Parse(t) => {
+                self.code.push(OP_PARSE);
+                self.value_type(t);
+            }
+            Instruction::Alloc(n) => {
+                self.code.push(OP_ALLOC);
+                self.u32(*n);
+            }
+            Instruction::HLoad => self.code.push(OP_HLOAD),
+            Instruction::HStore => self.code.push(OP_HSTORE),
+            Instruction::Free => self.code.push(OP_FREE),
+            Instruction::Addr(n) => {
+                self.code.push(OP_ADDR);
+                self.u32(*n);
+            }
+            Instruction::AGet => self.code.push(OP_AGET),
+            Instruction::ASet => self.code.push(OP_ASET),
+            Instruction::ALen => self.code.push(OP_ALEN),
+            Instruction::APush => self.code.push(OP_APUSH),
+            Instruction::Eq => self.code.push(OP_EQ),
+            Instruction::Ne => self.code.push(OP_NE),
+            Instruction::Lt => self.code.push(OP_LT),
+            Instruction::Le => self.code.push(OP_LE),
+            Instruction::Gt => self.code.push(OP_GT),
+            Instruction::Ge => self.code.push(OP_GE),
+            Instruction::Cast(t) => {
+                self.code.push(OP_CAST);
+                self.value_type(t);
+            }
+            Instruction::Exit(code) => {
+                self.code.push(OP_EXIT);
+                self.code.push(*code);
+            }
+            Instruction::Try(addr) => {
+                self.code.push(OP_TRY);
+                self.u32(*addr);
+            }
+            Instruction::EndTry => self.code.push(OP_ENDTRY),
+            Instruction::Throw => self.code.push(OP_THROW),
+            Instruction::Call(addr) => {
+                self.code.push(OP_CALL);
+                self.u32(*addr);
+            }
+            Instruction::Ret => self.code.push(OP_RET),
+            Instruction::Enter(n) => {
+                self.code.push(OP_ENTER);
+                self.u32(*n);
+            }
+            Instruction::Leave => self.code.push(OP_LEAVE),
+            Instruction::LLoad(i) => {
+                self.code.push(OP_LLOAD);
+                self.u32(*i);
+            }
+            Instruction::LStore(i) => {
+                self.code.push(OP_LSTORE);
+                self.u32(*i);
+            }
+            Instruction::Native(name, n) => {
+                self.code.push(OP_NATIVE);
+                self.string(name);
+                self.u32(*n);
+            }
+        }
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, v: usize) {
+    buf.extend_from_slice(&(v as u32).to_le_bytes());
+}
+
+// Rejects the instructions with an operand too large to be encoded, positioned by their source
+// line if known
+pub fn check_operands(prog: &Program, lines: Option<&LineTable>) -> result::Result<(), String> {
+    let mut encoder = Encoder::default();
+    for (addr, i) in prog.iter().enumerate() {
+        encoder.instruction(i);
+        if encoder.overflow {
+            let message = format!("{:?}: operand exceeds the maximum of {}", i, u32::MAX);
+            return Err(match lines.and_then(|l| l.get(addr)) {
+                Some(line) => format!("line {}: {}", line, message),
+                None => format!("{:04}: {}", addr, message),
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn encode(
+    prog: &Program,
+    lines: Option<&LineTable>,
+    data: &Data,
+) -> result::Result<Vec<u8>, String> {
+    check_operands(prog, lines)?;
+    let mut encoder = Encoder::default();
+    for i in prog {
+        encoder.instruction(i);
+    }
+    // Data values are encoded along with the code, as they may hold bigdecimal constants too
+    if !data.is_empty() {
+        encoder.u32(data.len());
+        for block in data {
+            encoder.u32(block.len());
+            for v in block {
+                encoder.value(v);
+            }
+        }
+    }
+
+    let mut payload = Vec::new();
+    push_u32(&mut payload, encoder.pool.len());
+    for constant in &encoder.pool {
+        push_u32(&mut payload, constant.len());
+        payload.extend_from_slice(constant.as_bytes());
+    }
+    push_u32(&mut payload, prog.len());
+    payload.extend_from_slice(&encoder.code);
+    if let Some(lines) = lines {
+        for line in lines {
+            push_u32(&mut payload, *line);
+        }
+    }
+
+    let mut flags = match lines {
+        Some(_) => FLAG_DEBUG,
+        None => 0,
+    };
+    if !data.is_empty() {
+        flags |= FLAG_DATA;
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&flags.to_le_bytes());
+    push_u32(&mut bytes, payload.len());
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/////////////////////////////
+// Decoding
+/////////////////////////////
+
+// Depth of the arrays and tuples nested in a value, bounding the recursion of the decoder
+const MAX_NESTING: usize = 256;
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    nesting: usize, // arrays and tuples the value being decoded is in
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> result::Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < n {
+            return Err(String::from("corrupt bytecode: unexpected end of payload"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> result::Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> result::Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> result::Result<String, String> {
+        let len = self.u32()? as usize;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(s) => Ok(String::from(s)),
+            Err(_) => Err(String::from("corrupt bytecode: invalid string")),
+        }
+    }
+
+    fn message(&mut self) -> result::Result<Option<String>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.string()?)),
+            b => Err(format!("corrupt bytecode: invalid presence byte 0x{:02x}", b)),
+        }
+    }
+
+    fn value_type(&mut self) -> result::Result<Type, String> {
+        match self.u8()? {
+            TAG_INT8 => Ok(Type::Int8),
+            TAG_INT16 => Ok(Type::Int16),
+            TAG_INT32 => Ok(Type::Int32),
+            TAG_FLOAT => Ok(Type::Float),
+            TAG_DOUBLE => Ok(Type::Double),
+            TAG_BIGDECIMAL => Ok(Type::BigDecimal),
+            TAG_STRING => Ok(Type::String),
+            TAG_ARRAY => Ok(Type::Array),
+            TAG_TUPLE => Ok(Type::Tuple),
+            TAG_BOOL => Ok(Type::Bool),
+            TAG_CHAR => Ok(Type::Char),
+            tag => Err(format!("corrupt bytecode: unknown value tag 0x{:02x}", tag)),
+        }
+    }
+
+    fn value(&mut self, pool: &[BigDecimal]) -> result::Result<Value, String> {
+        match self.u8()? {
+            TAG_INT8 => Ok(Value::Int8(self.u8()? as i8)),
+            TAG_INT16 => Ok(Value::Int16(i16::from_le_bytes(
+                self.take(2)?.try_into().unwrap(),
+            ))),
+            TAG_INT32 => Ok(Value::Int32(i32::from_le_bytes(
+                self.take(4)?.try_into().unwrap(),
+            ))),
+            TAG_FLOAT => Ok(Value::Float(f32::from_bits(self.u32()?))),
+            TAG_DOUBLE => Ok(Value::Double(f64::from_bits(u64::from_le_bytes(
+                self.take(8)?.try_into().unwrap(),
+            )))),
+            TAG_BIGDECIMAL => {
+                let index = self.u32()? as usize;
+                match pool.get(index) {
+                    Some(v) => Ok(Value::BigDecimal(v.clone())),
+                    None => Err(format!("corrupt bytecode: invalid constant index {}", index)),
+                }
+            }
+            TAG_STRING => Ok(Value::String(self.string()?)),
+            TAG_BOOL => match self.u8()? {
+                0 => Ok(Value::Bool(false)),
+                1 => Ok(Value::Bool(true)),
+                b => Err(format!("corrupt bytecode: invalid bool 0x{:02x}", b)),
+            },
+            TAG_CHAR => {
+                let code = self.u32()?;
+                match char::from_u32(code) {
+                    Some(c) => Ok(Value::Char(c)),
+                    None => Err(format!("corrupt bytecode: invalid char 0x{:x}", code)),
+                }
+            }
+            tag @ (TAG_ARRAY | TAG_TUPLE) => {
+                if self.nesting == MAX_NESTING {
+                    return Err(String::from("corrupt bytecode: values nested too deeply"));
+                }
+                self.nesting += 1;
+                let mut elements = Vec::new();
+                for _ in 0..self.u32()? {
+                    elements.push(self.value(pool)?);
+                }
+                self.nesting -= 1;
+                match tag {
+                    TAG_TUPLE => Ok(Value::Tuple(elements)),
+                    _ => match Value::array(elements) {
+                        Ok(v) => Ok(v),
+                        Err(e) => Err(format!("corrupt bytecode: {}", e)),
+                    },
+                }
+            }
+            tag => Err(format!("corrupt bytecode: unknown value tag 0x{:02x}", tag)),
+        }
+    }
+
+    fn register(&mut self) -> result::Result<usize, String> {
+        Ok(self.u8()? as usize) // any byte is below MAX_REGISTERS
+    }
+
+    fn instruction(&mut self, pool: &[BigDecimal]) -> result::Result<Instruction, String> {
+        match self.u8()? {
+            OP_NOOP => Ok(Instruction::Noop),
+            OP_PUSH => Ok(Instruction::Push(self.value(pool)?)),
+            OP_POP => Ok(Instruction::Pop),
+            OP_DUMP => Ok(Instruction::Dump),
+            OP_CLEAR => Ok(Instruction::Clear),
+            OP_DUP => Ok(Instruction::Dup),
+            OP_SWAP => Ok(Instruction::Swap),
+            OP_OVER => Ok(Instruction::Over),
+            OP_ROT => Ok(Instruction::Rot),
+            OP_MINUS_ROT => Ok(Instruction::MinusRot),
+            OP_NIP => Ok(Instruction::Nip),
+            OP_TUCK => Ok(Instruction::Tuck),
+            OP_PICK => Ok(Instruction::Pick(self.u32()? as usize)),
+            OP_ROLL => Ok(Instruction::Roll(self.u32()? as usize)),
+            OP_DROP => Ok(Instruction::Drop(self.u32()? as usize)),
+            OP_DEPTH => Ok(Instruction::Depth),
+            OP_ASSERT => Ok(Instruction::Assert(self.value(pool)?)),
+            OP_ASSERT_EQ => Ok(Instruction::AssertEq(self.message()?)),
+            OP_ASSERT_NE => Ok(Instruction::AssertNe(self.message()?)),
+            OP_ASSERT_LT => Ok(Instruction::AssertLt(self.message()?)),
+            OP_ASSERT_TYPE => Ok(Instruction::AssertType(
+                self.value_type()?,
+                self.message()?,
+            )),
+            OP_ASSERT_DEPTH => Ok(Instruction::AssertDepth(
+                self.u32()? as usize,
+                self.message()?,
+            )),
+            OP_ASSERT_APPROX => Ok(Instruction::AssertApprox(
+                self.value(pool)?,
+                self.value(pool)?,
+                self.message()?,
+            )),
+            OP_ASSERT_ULPS => Ok(Instruction::AssertUlps(
+                self.value(pool)?,
+                self.u32()?,
+                self.message()?,
+            )),
+            OP_ADD => Ok(Instruction::Add),
+            OP_SUB => Ok(Instruction::Sub),
+            OP_MUL => Ok(Instruction::Mul),
+            OP_DIV => Ok(Instruction::Div),
+            OP_MOD => Ok(Instruction::Mod),
+            OP_LOAD => Ok(Instruction::Load(self.register()?)),
+            OP_STORE => Ok(Instruction::Store(self.register()?)),
+            OP_ISSET => Ok(Instruction::IsSet(self.register()?)),
+            OP_REGCLEAR => Ok(Instruction::RegClear),
+            OP_PRINT => Ok(Instruction::Print),
+            OP_PRINTS => Ok(Instruction::Prints),
+            OP_PRINTV => Ok(Instruction::Printv),
+            OP_PRINTD => Ok(Instruction::Printd),
+            OP_PUTC => Ok(Instruction::Putc),
+            OP_PRINTF => Ok(Instruction::Printf(self.string()?, self.u32()? as usize)),
+            OP_CONCAT => Ok(Instruction::Concat),
+            OP_LEN => Ok(Instruction::Len),
+            OP_CHARAT => Ok(Instruction::CharAt),
+            OP_TOSTR => Ok(Instruction::ToStr),
+            OP_PARSE => Ok(Instruction::Parse(self.value_type()?)),
+            OP_ALLOC => Ok(Instruction::Alloc(self.u32()? as usize)),
+            OP_HLOAD => Ok(Instruction::HLoad),
+            OP_HSTORE => Ok(Instruction::HStore),
+            OP_FREE => Ok(Instruction::Free),
+            OP_ADDR => Ok(Instruction::Addr(self.u32()? as usize)),
+            OP_AGET => Ok(Instruction::AGet),
+            OP_ASET => Ok(Instruction::ASet),
+            OP_ALEN => Ok(Instruction::ALen),
+            OP_APUSH => Ok(Instruction::APush),
+            OP_EQ => Ok(Instruction::Eq),
+            OP_NE => Ok(Instruction::Ne),
+            OP_LT => Ok(Instruction::Lt),
+            OP_LE => Ok(Instruction::Le),
+            OP_GT => Ok(Instruction::Gt),
+            OP_GE => Ok(Instruction::Ge),
+            OP_CAST => Ok(Instruction::Cast(self.value_type()?)),
+            OP_EXIT => match self.u8()? {
+                code if is_exit_code(code) => Ok(Instruction::Exit(code)),
+                code => Err(format!("corrupt bytecode: invalid exit code {}", code)),
+            },
+            OP_TRY => Ok(Instruction::Try(self.u32()? as usize)),
+            OP_ENDTRY => Ok(Instruction::EndTry),
+            OP_THROW => Ok(Instruction::Throw),
+            OP_CALL => Ok(Instruction::Call(self.u32()? as usize)),
+            OP_RET => Ok(Instruction::Ret),
+            OP_ENTER => Ok(Instruction::Enter(self.u32()? as usize)),
+            OP_LEAVE => Ok(Instruction::Leave),
+            OP_LLOAD => Ok(Instruction::LLoad(self.u32()? as usize)),
+            OP_LSTORE => Ok(Instruction::LStore(self.u32()? as usize)),
+            OP_NATIVE => Ok(Instruction::Native(self.string()?, self.u32()? as usize)),
+            op => Err(format!("corrupt bytecode: unknown opcode 0x{:02x}", op)),
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> result::Result<(Program, Option<LineTable>, Data), String> {
+    // Header checking
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return Err(String::from("invalid bytecode: missing AVMC header"));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(format!(
+            "invalid bytecode: unsupported version {} (expected {})",
+            version, VERSION
+        ));
+    }
+    let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+    let len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(format!(
+            "invalid bytecode: truncated file (payload is {} bytes, expected {})",
+            payload.len(),
+            len
+        ));
+    }
+    if crc32(payload) != checksum {
+        return Err(String::from("invalid bytecode: checksum mismatch"));
+    }
+
+    // Payload decoding
+    let mut decoder = Decoder {
+        bytes: payload,
+        pos: 0,
+        nesting: 0,
+    };
+    let mut pool = Vec::new();
+    for _ in 0..decoder.u32()? {
+        let len = decoder.u32()? as usize;
+        let constant = decoder.take(len)?;
+        match std::str::from_utf8(constant).map(|s| s.parse::<BigDecimal>()) {
+            Ok(Ok(v)) if plain_digits(&v) <= MAX_LITERAL_DIGITS => pool.push(v),
+            _ => return Err(String::from("corrupt bytecode: invalid bigdecimal constant")),
+        }
+    }
+    let count = decoder.u32()? as usize;
+    let mut prog = Program::new();
+    for _ in 0..count {
+        prog.push(decoder.instruction(&pool)?);
+    }
+    let mut data = Data::new();
+    if flags & FLAG_DATA != 0 {
+        for _ in 0..decoder.u32()? {
+            let mut block = Vec::new();
+            for _ in 0..decoder.u32()? {
+                block.push(decoder.value(&pool)?);
+            }
+            if block.is_empty() {
+                return Err(String::from("corrupt bytecode: empty data block"));
+            }
+            data.push(block);
+        }
+    }
+    let lines = match flags & FLAG_DEBUG {
+        0 => None,
+        _ => {
+            let mut lines = LineTable::new();
+            for _ in 0..count {
+                lines.push(decoder.u32()? as usize);
+            }
+            Some(lines)
+        }
+    };
+    if decoder.pos != payload.len() {
+        return Err(String::from("corrupt bytecode: trailing bytes after payload"));
+    }
+    Ok((prog, lines, data))
+}
+
+/////////////////////////////
+// Files
+/////////////////////////////
+
+pub fn load_bytecode(
+    filename: &str,
+) -> result::Result<(Program, Option<LineTable>, Data), String> {
+    match fs::read(filename) {
+        Err(why) => Err(format!("couldn't open {}: {}", filename, why)),
+        Ok(bytes) => match decode(&bytes) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(format!("{}: {}", filename, e)),
+        },
+    }
+}
+
+pub fn write_bytecode(
+    filename: &str,
+    prog: &Program,
+    lines: Option<&LineTable>,
+    data: &Data,
+) -> result::Result<(), String> {
+    match fs::write(filename, encode(prog, lines, data)?) {
+        Err(why) => Err(format!("couldn't write {}: {}", filename, why)),
+        Ok(_) => Ok(()),
+    }
+}
+
+// CRC-32 (IEEE 802.3), computed bitwise as programs are small
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xedb88320,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::parse_source;
+
+    // Program using every kind of operand, with its line table and data blocks
+    fn sample() -> (Program, LineTable, Data) {
+        let text = "push bigdecimal(1.25)\n\
+                    push array(tuple(int8(1), string(\"a;b\")), tuple(char('x'), bool(true)))\n\
+                    assert_ulps float(0.5) 4 \"close\"\n\
+                    assert_depth 2\n\
+                    store r3\n\
+                    printf \"{} and {:?}\" 2\n\
+                    cast double\n\
+                    try 9\n\
+                    native hash 1\n\
+                    exit int8(3)";
+        let source = parse_source(text).unwrap();
+        let prog: Program = source.into_iter().flat_map(|l| l.instructions).collect();
+        let lines = (1..=prog.len()).collect();
+        let data = vec![
+            vec![Value::BigDecimal(BigDecimal::from(7)), Value::Int32(-1)],
+            vec![Value::Double(f64::NAN)],
+        ];
+        (prog, lines, data)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (prog, lines, data) = sample();
+        let (decoded, decoded_lines, decoded_data) =
+            decode(&encode(&prog, Some(&lines), &data).unwrap()).unwrap();
+        assert!(decoded == prog);
+        assert_eq!(decoded_lines, Some(lines));
+        // NaN is never equal to itself
+        assert_eq!(format!("{:?}", decoded_data), format!("{:?}", data));
+
+        let (decoded, decoded_lines, decoded_data) =
+            decode(&encode(&prog, None, &Data::new()).unwrap()).unwrap();
+        assert!(decoded == prog);
+        assert_eq!(decoded_lines, None);
+        assert!(decoded_data.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let (prog, lines, data) = sample();
+        let bytes = encode(&prog, Some(&lines), &data).unwrap();
+        assert_eq!(
+            decode(&bytes[..HEADER_LEN - 1]),
+            Err(String::from("invalid bytecode: missing AVMC header"))
+        );
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(format!(
+                "invalid bytecode: truncated file (payload is {} bytes, expected {})",
+                bytes.len() - HEADER_LEN - 1,
+                bytes.len() - HEADER_LEN
+            ))
+        );
+    }
+
+    // Bytes of a program whose payload is replaced, with a consistent header
+    fn with_payload(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        push_u32(&mut bytes, payload.len());
+        bytes.extend_from_slice(&crc32(payload).to_le_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn bad_header() {
+        let (prog, lines, data) = sample();
+        let bytes = encode(&prog, Some(&lines), &data).unwrap();
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(decode(&magic), Err(String::from("invalid bytecode: missing AVMC header")));
+
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(
+            decode(&version),
+            Err(String::from("invalid bytecode: unsupported version 2 (expected 1)"))
+        );
+
+        let mut checksum = bytes.clone();
+        *checksum.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&checksum), Err(String::from("invalid bytecode: checksum mismatch")));
+    }
+
+    #[test]
+    fn corrupt_payload() {
+        // No constants, one instruction of unknown opcode
+        let payload = [0, 0, 0, 0, 1, 0, 0, 0, 0xff];
+        assert_eq!(
+            decode(&with_payload(&payload)),
+            Err(String::from("corrupt bytecode: unknown opcode 0xff"))
+        );
+        // A push missing its value
+        let payload = [0, 0, 0, 0, 1, 0, 0, 0, OP_PUSH];
+        assert_eq!(
+            decode(&with_payload(&payload)),
+            Err(String::from("corrupt bytecode: unexpected end of payload"))
+        );
+        let payload = [0, 0, 0, 0, 1, 0, 0, 0, OP_NOOP, 0];
+        assert_eq!(
+            decode(&with_payload(&payload)),
+            Err(String::from("corrupt bytecode: trailing bytes after payload"))
+        );
+        // An exit code the assembler rejects
+        let payload = [0, 0, 0, 0, 1, 0, 0, 0, OP_EXIT, 84];
+        assert_eq!(
+            decode(&with_payload(&payload)),
+            Err(String::from("corrupt bytecode: invalid exit code 84"))
+        );
+        // A push of arrays of one array each, nested deeper than the limit
+        let mut payload = vec![0, 0, 0, 0, 1, 0, 0, 0, OP_PUSH];
+        for _ in 0..=MAX_NESTING {
+            payload.extend([TAG_ARRAY, 1, 0, 0, 0]);
+        }
+        payload.extend([TAG_INT8, 0]);
+        assert_eq!(
+            decode(&with_payload(&payload)),
+            Err(String::from("corrupt bytecode: values nested too deeply"))
+        );
+    }
+
+    #[test]
+    fn operand_overflow() {
+        let prog = vec![Instruction::Noop, Instruction::Alloc(u32::MAX as usize + 1)];
+        let lines = vec![1, 3];
+        assert_eq!(
+            encode(&prog, Some(&lines), &Data::new()),
+            Err(String::from("line 3: alloc 4294967296: operand exceeds the maximum of 4294967295"))
+        );
+        assert_eq!(
+            check_operands(&prog, None),
+            Err(String::from("0001: alloc 4294967296: operand exceeds the maximum of 4294967295"))
+        );
+    }
+}
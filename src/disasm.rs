@@ -0,0 +1,101 @@
+use crate::instruction::Instruction;
+use crate::program::{Data, LineTable, Program};
+use crate::value::Value;
+
+// Width of the instruction column, trailing comments being aligned after it
+const INSTRUCTION_WIDTH: usize = 32;
+
+// Canonical assembly of a program: one instruction per line, followed by a comment giving its
+// address and, if debug information is available, its source line number. The output can be
+// assembled back to the same program. Code labels are not kept by the bytecode either: the
+// targets of `try` and `call` are named after their address (`label6`).
+pub fn disassemble(prog: &Program, lines: Option<&LineTable>, data: &Data) -> String {
+    let mut out = String::new();
+    // Data labels are not kept by the bytecode, blocks are named after their number instead
+    if !data.is_empty() {
+        out.push_str(".data\n");
+        for (n, block) in data.iter().enumerate() {
+            let values: Vec<String> = block.iter().map(payload).collect();
+            let t = block[0].get_type();
+            out.push_str(&format!("data{}: .{} {}\n", n, t, values.join(", ")));
+        }
+        out.push_str(".text\n");
+    }
+    let mut targets = vec![false; prog.len() + 1];
+    for instruction in prog {
+        if let Instruction::Try(target) | Instruction::Call(target) = instruction {
+            if let Some(t) = targets.get_mut(*target) {
+                *t = true;
+            }
+        }
+    }
+    for (addr, instruction) in prog.iter().enumerate() {
+        if targets[addr] {
+            out.push_str(&format!("label{}:\n", addr));
+        }
+        // Targets out of the program have no label, and are written as addresses
+        let text = match instruction {
+            Instruction::Try(target) if *target <= prog.len() => {
+                format!("try label{}", target)
+            }
+            Instruction::Call(target) if *target <= prog.len() => {
+                format!("call label{}", target)
+            }
+            Instruction::Addr(n) if *n < data.len() => format!("addr data{}", n),
+            _ => format!("{:?}", instruction),
+        };
+        let location = match lines.and_then(|l| l.get(addr)) {
+            Some(line) => format!("{:04} (line {})", addr, line),
+            None => format!("{:04}", addr),
+        };
+        out.push_str(&format!(
+            "{:<width$} ; {}\n",
+            text,
+            location,
+            width = INSTRUCTION_WIDTH
+        ));
+    }
+    if targets[prog.len()] {
+        out.push_str(&format!("label{}:\n", prog.len()));
+    }
+    out
+}
+
+// Value as written in a data declaration, without its type
+fn payload(v: &Value) -> String {
+    let text = format!("{:?}", v);
+    let t = v.get_type().to_string();
+    String::from(&text[t.len() + 1..text.len() - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        let prog = vec![
+            Instruction::Try(3),
+            Instruction::Call(4),
+            Instruction::Addr(0),
+            Instruction::Call(9),
+        ];
+        let data = vec![vec![Value::Int8(1)]];
+        let text = disassemble(&prog, None, &data);
+        let code: Vec<&str> = text.lines().map(|l| l.split(" ;").next().unwrap().trim()).collect();
+        assert_eq!(
+            code,
+            [
+                ".data",
+                "data0: .int8 1",
+                ".text",
+                "try label3",
+                "call label4",
+                "addr data0",
+                "label3:",
+                "call 9",
+                "label4:"
+            ]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::result;
+
+use crate::value::{Type, Value};
+
+// Assemble-time constant expression, as the payload of a numeric operand (`int32(SIZE * 4 + 1)`):
+// literals of the operand type, constants declared by `.const`, `+`, `-`, `*`, `/` and `%` with the
+// usual precedence, unary minus and parentheses. Operations are the VM ones, so that an overflow or
+// a division by zero is an error here as it would be at run time.
+
+#[derive(Clone, PartialEq)]
+enum Token<'a> {
+    Number(&'a str),
+    Name(&'a str),
+    Operator(char),
+}
+
+fn tokenize(s: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        match c {
+            c if c.is_whitespace() => continue,
+            '+' | '-' | '*' | '/' | '%' | '(' | ')' => tokens.push(Token::Operator(c)),
+            c if c.is_ascii_digit() || c == '.' => {
+                while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    end = i + c.len_utf8();
+                }
+                // Such as the exponent of a literal, which is not an expression
+                if chars.next_if(|(_, c)| c.is_ascii_alphabetic() || *c == '_').is_some() {
+                    return None;
+                }
+                tokens.push(Token::Number(&s[start..end]));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    end = i + c.len_utf8();
+                }
+                tokens.push(Token::Name(&s[start..end]));
+            }
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+struct Parser<'a, 'b> {
+    tokens: Vec<Token<'a>>,
+    next: usize,
+    t: Type, // type of the literals
+    constants: &'b HashMap<String, Value>,
+    text: &'a str, // whole operand, for error messages
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.next).cloned()
+    }
+
+    fn literal(&self, s: &str) -> result::Result<Value, String> {
+        Value::parse(&format!("{}({})", self.t, s))
+    }
+
+    fn apply(&self, a: Value, op: char, b: Value) -> result::Result<Value, String> {
+        let result = match op {
+            '+' => a + b,
+            '-' => a - b,
+            '*' => a * b,
+            '/' => a / b,
+            _ => a % b,
+        };
+        match result {
+            Ok(v) => Ok(v),
+            Err(_) => Err(format!("arithmetic error in constant expression: {}", self.text)),
+        }
+    }
+
+    // expression: term (('+' | '-') term)*
+    fn expression(&mut self) -> result::Result<Value, String> {
+        let mut v = self.term()?;
+        while let Some(Token::Operator(op @ ('+' | '-'))) = self.peek() {
+            self.next += 1;
+            let w = self.term()?;
+            v = self.apply(v, op, w)?;
+        }
+        Ok(v)
+    }
+
+    // term: unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> result::Result<Value, String> {
+        let mut v = self.unary()?;
+        while let Some(Token::Operator(op @ ('*' | '/' | '%'))) = self.peek() {
+            self.next += 1;
+            let w = self.unary()?;
+            v = self.apply(v, op, w)?;
+        }
+        Ok(v)
+    }
+
+    // unary: '-' unary | number | name | '(' expression ')'
+    fn unary(&mut self) -> result::Result<Value, String> {
+        let token = self.peek();
+        self.next += 1;
+        match token {
+            // Negative literals are read as such, the lowest integer having no positive counterpart
+            Some(Token::Operator('-')) => match self.peek() {
+                Some(Token::Number(n)) => {
+                    self.next += 1;
+                    self.literal(&format!("-{}", n))
+                }
+                _ => {
+                    let v = self.unary()?;
+                    self.apply(self.literal("0")?, '-', v)
+                }
+            },
+            Some(Token::Number(n)) => self.literal(n),
+            Some(Token::Name(name)) => match self.constants.get(name) {
+                Some(v) => Ok(v.clone()),
+                None => Err(format!("unknown constant: {}", name)),
+            },
+            Some(Token::Operator('(')) => {
+                let v = self.expression()?;
+                match self.peek() {
+                    Some(Token::Operator(')')) => {
+                        self.next += 1;
+                        Ok(v)
+                    }
+                    _ => Err(format!("syntax error: missing ')': {}", self.text)),
+                }
+            }
+            _ => Err(format!("syntax error: illegal constant expression: {}", self.text)),
+        }
+    }
+}
+
+// Value of a numeric operand whose payload is a constant expression, converted to the operand type.
+// `None` if the operand is not of this form, so that its own syntax error can be reported instead.
+pub fn evaluate(
+    s: &str,
+    constants: &HashMap<String, Value>,
+) -> result::Result<Option<Value>, String> {
+    let (t, payload) = match (s.find('('), s.strip_suffix(')')) {
+        (Some(a), Some(rest)) => match Type::parse(s[..a].trim()) {
+            Ok(t) if t <= Type::BigDecimal => (t, &rest[a + 1..]),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let tokens = match tokenize(payload) {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+    let mut parser = Parser {
+        tokens,
+        next: 0,
+        t,
+        constants,
+        text: s,
+    };
+    let v = parser.expression()?;
+    if parser.next < parser.tokens.len() {
+        return Err(format!("syntax error: illegal constant expression: {}", s));
+    }
+    // Lower ranked results are promoted to the operand type, higher ranked ones must fit in it
+    let converted = match v.get_type() {
+        vt if vt < t => v.promote_to(t),
+        _ => v.cast(t).ok(),
+    };
+    match converted {
+        Some(converted) => Ok(Some(converted)),
+        None => Err(format!("cannot convert {:?} to {}: {}", v, t, s)),
+    }
+}
@@ -0,0 +1,74 @@
+use crate::program::SourceLine;
+
+// Canonical form of an assembly source: instructions are written with their `Debug` formatting
+// (lowercase opcode, single space before the operand, normalized value literal), except for the
+// symbols they use (see `SourceLine::code`), directives with normalized spacing, and the lines of macro
+// bodies as written, indented by 4 spaces; trailing comments
+// are aligned one column after the longest commented instruction, and blank lines and full-line
+// comments are kept as is.
+pub fn format_source(source: &[SourceLine]) -> String {
+    let lines: Vec<(&Option<String>, &Option<String>)> =
+        source.iter().map(|l| (&l.code, &l.comment)).collect();
+    let comment_column = lines
+        .iter()
+        .filter_map(|l| match l {
+            (Some(code), Some(_)) => Some(code.chars().count()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for line in lines {
+        match line {
+            (Some(code), Some(comment)) => out.push_str(&format!(
+                "{:<width$} ;{}",
+                code,
+                comment,
+                width = comment_column
+            )),
+            (Some(code), None) => out.push_str(code),
+            (None, Some(comment)) => out.push_str(&format!(";{}", comment)),
+            (None, None) => {}
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::parse_source;
+
+    fn format(text: &str) -> String {
+        format_source(&parse_source(text).unwrap())
+    }
+
+    #[test]
+    fn canonical() {
+        let text = "PUSH  int8(+1)   ; one\n\
+                    push string(\"été\") ; accents\n\
+                    \n\
+                    ; full line\n\
+                    .macro twice v\n\
+                    push \\v\n\
+                    push \\v\n\
+                    .endm\n\
+                    twice int8(2)\n\
+                    exit";
+        let formatted = "push int8(1)       ; one\n\
+                         push string(\"été\") ; accents\n\
+                         \n\
+                         ; full line\n\
+                         .macro twice v\n    \
+                         push \\v\n    \
+                         push \\v\n\
+                         .endm\n\
+                         twice int8(2)\n\
+                         exit\n";
+        assert_eq!(format(text), formatted);
+        // Formatting a formatted source changes nothing
+        assert_eq!(format(formatted), formatted);
+    }
+}
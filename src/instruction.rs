@@ -1,8 +1,12 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter, Result};
 use std::result;
 
-use crate::value::Value;
+use crate::expr;
+use crate::printf::placeholders;
+use crate::value::{escape, unescape, Type, Value};
 
+#[derive(Clone, PartialEq)]
 pub enum Instruction {
     Noop,
     Push(Value),
@@ -11,7 +15,23 @@ pub enum Instruction {
     Clear,
     Dup,
     Swap,
+    Over,
+    Rot,
+    MinusRot,
+    Nip,
+    Tuck,
+    Pick(usize),
+    Roll(usize),
+    Drop(usize),
+    Depth,
     Assert(Value),
+    AssertEq(Option<String>),
+    AssertNe(Option<String>),
+    AssertLt(Option<String>),
+    AssertType(Type, Option<String>),
+    AssertDepth(usize, Option<String>),
+    AssertApprox(Value, Value, Option<String>),
+    AssertUlps(Value, u32, Option<String>),
     Add,
     Sub,
     Mul,
@@ -19,13 +39,51 @@ pub enum Instruction {
     Mod,
     Load(usize),
     Store(usize),
+    IsSet(usize),
+    RegClear,
     Print,
-    Exit,
+    Prints,
+    Printv,
+    Printd,
+    Putc,
+    Printf(String, usize),
+    Alloc(usize),
+    HLoad,
+    HStore,
+    Free,
+    Addr(usize),
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Cast(Type),
+    AGet,
+    ASet,
+    ALen,
+    APush,
+    Concat,
+    Len,
+    CharAt,
+    ToStr,
+    Parse(Type),
+    Exit(u8),
+    Try(usize),
+    EndTry,
+    Throw,
+    Call(usize),
+    Ret,
+    Enter(usize),
+    Leave,
+    LLoad(usize),
+    LStore(usize),
+    Native(String, usize),
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{}", format!("{:?}", self))
+        write!(f, "{:?}", self)
     }
 }
 
@@ -33,36 +91,140 @@ impl Debug for Instruction {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
             Instruction::Noop => write!(f, "noop"),
-            Instruction::Push(v) => write!(f, "push {}", v),
+            Instruction::Push(v) => write!(f, "push {:?}", v),
             Instruction::Pop => write!(f, "pop"),
             Instruction::Dump => write!(f, "dump"),
             Instruction::Clear => write!(f, "clear"),
             Instruction::Dup => write!(f, "dup"),
             Instruction::Swap => write!(f, "swap"),
-            Instruction::Assert(v) => write!(f, "assert{}", v),
+            Instruction::Over => write!(f, "over"),
+            Instruction::Rot => write!(f, "rot"),
+            Instruction::MinusRot => write!(f, "-rot"),
+            Instruction::Nip => write!(f, "nip"),
+            Instruction::Tuck => write!(f, "tuck"),
+            Instruction::Pick(n) => write!(f, "pick {}", n),
+            Instruction::Roll(n) => write!(f, "roll {}", n),
+            Instruction::Drop(n) => write!(f, "drop {}", n),
+            Instruction::Depth => write!(f, "depth"),
+            Instruction::Assert(v) => write!(f, "assert {:?}", v),
+            Instruction::AssertEq(m) => write!(f, "assert_eq{}", Message(m)),
+            Instruction::AssertNe(m) => write!(f, "assert_ne{}", Message(m)),
+            Instruction::AssertLt(m) => write!(f, "assert_lt{}", Message(m)),
+            Instruction::AssertType(t, m) => write!(f, "assert_type {}{}", t, Message(m)),
+            Instruction::AssertDepth(n, m) => write!(f, "assert_depth {}{}", n, Message(m)),
+            Instruction::AssertApprox(v, e, m) => {
+                write!(f, "assert_approx {:?} {:?}{}", v, e, Message(m))
+            }
+            Instruction::AssertUlps(v, n, m) => {
+                write!(f, "assert_ulps {:?} {}{}", v, n, Message(m))
+            }
             Instruction::Add => write!(f, "add"),
             Instruction::Sub => write!(f, "sub"),
             Instruction::Mul => write!(f, "mul"),
             Instruction::Div => write!(f, "div"),
             Instruction::Mod => write!(f, "mod"),
-            Instruction::Load(v) => write!(f, "load {}", v),
-            Instruction::Store(v) => write!(f, "store {}", v),
+            Instruction::Load(r) => write!(f, "load r{}", r),
+            Instruction::Store(r) => write!(f, "store r{}", r),
+            Instruction::IsSet(r) => write!(f, "isset r{}", r),
+            Instruction::RegClear => write!(f, "regclear"),
             Instruction::Print => write!(f, "print"),
-            Instruction::Exit => write!(f, "exit"),
+            Instruction::Prints => write!(f, "prints"),
+            Instruction::Printv => write!(f, "printv"),
+            Instruction::Printd => write!(f, "printd"),
+            Instruction::Putc => write!(f, "putc"),
+            Instruction::Printf(fmt, n) => write!(f, "printf \"{}\" {}", escape(fmt), n),
+            Instruction::Alloc(n) => write!(f, "alloc {}", n),
+            Instruction::HLoad => write!(f, "hload"),
+            Instruction::HStore => write!(f, "hstore"),
+            Instruction::Free => write!(f, "free"),
+            Instruction::Addr(n) => write!(f, "addr {}", n),
+            Instruction::Eq => write!(f, "eq"),
+            Instruction::Ne => write!(f, "ne"),
+            Instruction::Lt => write!(f, "lt"),
+            Instruction::Le => write!(f, "le"),
+            Instruction::Gt => write!(f, "gt"),
+            Instruction::Ge => write!(f, "ge"),
+            Instruction::Cast(t) => write!(f, "cast {}", t),
+            Instruction::AGet => write!(f, "aget"),
+            Instruction::ASet => write!(f, "aset"),
+            Instruction::ALen => write!(f, "alen"),
+            Instruction::APush => write!(f, "apush"),
+            Instruction::Concat => write!(f, "concat"),
+            Instruction::Len => write!(f, "len"),
+            Instruction::CharAt => write!(f, "charat"),
+            Instruction::ToStr => write!(f, "tostr"),
+            Instruction::Parse(t) => write!(f, "parse {}", t),
+            Instruction::Exit(0) => write!(f, "exit"),
+            Instruction::Exit(v) => write!(f, "exit int8({})", v),
+            Instruction::Try(addr) => write!(f, "try {}", addr),
+            Instruction::EndTry => write!(f, "endtry"),
+            Instruction::Throw => write!(f, "throw"),
+            Instruction::Call(addr) => write!(f, "call {}", addr),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Enter(n) => write!(f, "enter {}", n),
+            Instruction::Leave => write!(f, "leave"),
+            Instruction::LLoad(i) => write!(f, "lload {}", i),
+            Instruction::LStore(i) => write!(f, "lstore {}", i),
+            Instruction::Native(name, n) => write!(f, "native {} {}", name, n),
         }
     }
 }
 
+// Optional message of an assertion, written after its operand if any
+struct Message<'a>(&'a Option<String>);
+
+impl Display for Message<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.0 {
+            Some(m) => write!(f, " \"{}\"", escape(m)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Names declared by the directives of an assembly source, that its instructions may use
+#[derive(Clone, Default)]
+pub struct Symbols {
+    pub registers: HashMap<String, usize>, // aliases declared by `.reg <name> <register>`
+    pub constants: HashMap<String, Value>, // values declared by `.const <name> = <value>`
+    pub data: HashMap<String, usize>,      // labels of the `.data` section, by block number
+    pub labels: HashMap<String, usize>,    // labels of the code, by instruction address
+    pub later: HashMap<String, usize>,     // labels defined further in the file, used before
+    pub expressions: bool, // whether numeric operands may be constant expressions (see `expr`)
+    // Whether unknown labels are taken as address 0, while the addresses of the labels are being
+    // collected
+    pub any_label: bool,
+}
+
+impl Symbols {
+    // Whether a name is declared, as a register alias, a constant, a data label or a code label
+    pub fn declares(&self, name: &str) -> bool {
+        self.registers.contains_key(name)
+            || self.constants.contains_key(name)
+            || self.data.contains_key(name)
+            || self.labels.contains_key(name)
+    }
+}
+
 impl Instruction {
-    pub fn parse(s: &str) -> result::Result<Instruction, String> {
+    // Whether a word is the opcode of an instruction, whatever its operands
+    pub fn is_opcode(word: &str) -> bool {
+        match Instruction::parse(word, &Symbols::default()) {
+            Ok(_) => true,
+            Err(e) => !e.starts_with("syntax error: unknown instruction"),
+        }
+    }
+
+    pub fn parse(s: &str, symbols: &Symbols) -> result::Result<Instruction, String> {
         let first_offset = s.find(' ');
         let (opcode, value) = match first_offset {
             Some(v) => (&s[..v], &s[v + 1..]),
             None => (s, ""),
         };
-        match opcode {
+        // Opcodes are case insensitive, the canonical form (see `abstractvm fmt`) being lowercase
+        match opcode.to_ascii_lowercase().as_str() {
             "noop" => Ok(Instruction::Noop),
-            "push" => match Value::parse(value.trim()) {
+            "push" => match parse_value(value.trim(), symbols) {
                 Ok(v) => Ok(Instruction::Push(v)),
                 Err(e) => Err(e),
             },
@@ -71,41 +233,658 @@ impl Instruction {
             "clear" => Ok(Instruction::Clear),
             "dup" => Ok(Instruction::Dup),
             "swap" => Ok(Instruction::Swap),
-            "assert" => match Value::parse(value.trim()) {
+            "over" => Ok(Instruction::Over),
+            "rot" => Ok(Instruction::Rot),
+            "-rot" => Ok(Instruction::MinusRot),
+            "nip" => Ok(Instruction::Nip),
+            "tuck" => Ok(Instruction::Tuck),
+            "pick" => match value.trim().parse::<usize>() {
+                Ok(n) => Ok(Instruction::Pick(n)),
+                Err(_) => Err(format!("syntax error: illegal stack index: {}", value.trim())),
+            },
+            "roll" => match value.trim().parse::<usize>() {
+                Ok(n) => Ok(Instruction::Roll(n)),
+                Err(_) => Err(format!("syntax error: illegal stack index: {}", value.trim())),
+            },
+            "drop" => match value.trim().parse::<usize>() {
+                Ok(n) => Ok(Instruction::Drop(n)),
+                Err(_) => Err(format!("syntax error: illegal value count: {}", value.trim())),
+            },
+            "depth" => Ok(Instruction::Depth),
+            "assert" => match parse_value(value.trim(), symbols) {
                 Ok(v) => Ok(Instruction::Assert(v)),
                 Err(e) => Err(e),
             },
+            "assert_eq" => match split_message(value) {
+                Ok(("", m)) => Ok(Instruction::AssertEq(m)),
+                Ok((v, _)) => Err(format!("syntax error: unexpected operand: {}", v)),
+                Err(e) => Err(e),
+            },
+            "assert_ne" => match split_message(value) {
+                Ok(("", m)) => Ok(Instruction::AssertNe(m)),
+                Ok((v, _)) => Err(format!("syntax error: unexpected operand: {}", v)),
+                Err(e) => Err(e),
+            },
+            "assert_lt" => match split_message(value) {
+                Ok(("", m)) => Ok(Instruction::AssertLt(m)),
+                Ok((v, _)) => Err(format!("syntax error: unexpected operand: {}", v)),
+                Err(e) => Err(e),
+            },
+            "assert_type" => match split_message(value) {
+                Ok((t, m)) => match Type::parse(t) {
+                    Ok(t) => Ok(Instruction::AssertType(t, m)),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            },
+            "assert_depth" => match split_message(value) {
+                Ok((n, m)) => match n.parse::<usize>() {
+                    Ok(n) => Ok(Instruction::AssertDepth(n, m)),
+                    Err(_) => Err(format!("syntax error: illegal stack depth: {}", n)),
+                },
+                Err(e) => Err(e),
+            },
+            "assert_approx" => match split_message(value) {
+                Ok((operands, m)) => {
+                    let (v, e) = split_operand(operands, symbols);
+                    match (parse_value(v, symbols), parse_value(e, symbols)) {
+                        (Ok(v), Ok(e)) => Ok(Instruction::AssertApprox(v, e, m)),
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            },
+            "assert_ulps" => match split_message(value) {
+                Ok((operands, m)) => {
+                    let (v, n) = split_operand(operands, symbols);
+                    match (parse_value(v, symbols), n.parse::<u32>()) {
+                        (Ok(v), Ok(n)) => Ok(Instruction::AssertUlps(v, n, m)),
+                        (Err(e), _) => Err(e),
+                        (_, Err(_)) => Err(format!("syntax error: illegal ulps count: {}", n)),
+                    }
+                }
+                Err(e) => Err(e),
+            },
             "add" => Ok(Instruction::Add),
             "sub" => Ok(Instruction::Sub),
             "mul" => Ok(Instruction::Mul),
             "div" => Ok(Instruction::Div),
             "mod" => Ok(Instruction::Mod),
-            "load" => match parse_reg(value.trim()) {
-                Ok(v) => Ok(Instruction::Load(v)),
+            "load" => match parse_reg(value.trim(), symbols) {
+                Ok(r) => Ok(Instruction::Load(r)),
+                Err(e) => Err(e),
+            },
+            "store" => match parse_reg(value.trim(), symbols) {
+                Ok(r) => Ok(Instruction::Store(r)),
                 Err(e) => Err(e),
             },
-            "store" => match parse_reg(value.trim()) {
-                Ok(v) => Ok(Instruction::Store(v)),
+            "isset" => match parse_reg(value.trim(), symbols) {
+                Ok(r) => Ok(Instruction::IsSet(r)),
                 Err(e) => Err(e),
             },
+            "regclear" => Ok(Instruction::RegClear),
             "print" => Ok(Instruction::Print),
-            "exit" => Ok(Instruction::Exit),
+            "prints" => Ok(Instruction::Prints),
+            "printv" => Ok(Instruction::Printv),
+            "printd" => Ok(Instruction::Printd),
+            "putc" => Ok(Instruction::Putc),
+            "printf" => parse_printf(value.trim()),
+            "alloc" => match value.trim().parse::<usize>() {
+                Ok(n) => Ok(Instruction::Alloc(n)),
+                Err(_) => Err(format!("syntax error: illegal block size: {}", value.trim())),
+            },
+            "hload" => Ok(Instruction::HLoad),
+            "hstore" => Ok(Instruction::HStore),
+            "free" => Ok(Instruction::Free),
+            "addr" => match symbols.data.get(value.trim()) {
+                Some(n) => Ok(Instruction::Addr(*n)),
+                None => match value.trim().parse::<usize>() {
+                    Ok(n) => Ok(Instruction::Addr(n)),
+                    Err(_) => Err(format!("syntax error: unknown data label: {}", value.trim())),
+                },
+            },
+            "eq" => Ok(Instruction::Eq),
+            "ne" => Ok(Instruction::Ne),
+            "lt" => Ok(Instruction::Lt),
+            "le" => Ok(Instruction::Le),
+            "gt" => Ok(Instruction::Gt),
+            "ge" => Ok(Instruction::Ge),
+            "cast" => match Type::parse(value.trim()) {
+                Ok(t) => Ok(Instruction::Cast(t)),
+                Err(e) => Err(e),
+            },
+            "aget" => Ok(Instruction::AGet),
+            "aset" => Ok(Instruction::ASet),
+            "alen" => Ok(Instruction::ALen),
+            "apush" => Ok(Instruction::APush),
+            "concat" => Ok(Instruction::Concat),
+            "len" => Ok(Instruction::Len),
+            "charat" => Ok(Instruction::CharAt),
+            "tostr" => Ok(Instruction::ToStr),
+            "parse" => match Type::parse(value.trim()) {
+                Ok(t) => Ok(Instruction::Parse(t)),
+                Err(e) => Err(e),
+            },
+            "exit" => match parse_exit_code(value.trim(), symbols) {
+                Ok(v) => Ok(Instruction::Exit(v)),
+                Err(e) => Err(e),
+            },
+            "try" => Ok(Instruction::Try(parse_label(value.trim(), symbols)?)),
+            "endtry" => Ok(Instruction::EndTry),
+            "throw" => Ok(Instruction::Throw),
+            "call" => Ok(Instruction::Call(parse_label(value.trim(), symbols)?)),
+            "ret" => Ok(Instruction::Ret),
+            "enter" => Ok(Instruction::Enter(parse_slot(value.trim(), symbols, "size")?)),
+            "leave" => Ok(Instruction::Leave),
+            "lload" => Ok(Instruction::LLoad(parse_slot(value.trim(), symbols, "slot")?)),
+            "lstore" => Ok(Instruction::LStore(parse_slot(value.trim(), symbols, "slot")?)),
+            "native" => parse_native(value.trim()),
             _ => Err(format!("syntax error: unknown instruction: {}", s)),
         }
     }
 }
 
-fn parse_reg(s: &str) -> result::Result<usize, String> {
+// Offset of the first character of an operand list matching the predicate, given the nesting
+// depth of parentheses at this character. Characters of string and char literals are skipped.
+fn find_unquoted(s: &str, pred: impl Fn(char, usize) -> bool) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = s.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', Some(_)) => {
+                chars.next(); // escaped character, possibly a quote
+                continue;
+            }
+            ('"' | '\'', None) => {
+                if pred(c, depth) {
+                    return Some(offset);
+                }
+                quote = Some(c);
+                continue;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            ('(', None) => depth += 1,
+            (')', None) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if quote.is_none() && pred(c, depth) {
+            return Some(offset);
+        }
+    }
+    None
+}
+
+// Splits the operands of an assertion from its optional message, written between double quotes
+// after them
+fn split_message(s: &str) -> result::Result<(&str, Option<String>), String> {
+    match find_unquoted(s, |c, depth| c == '"' && depth == 0) {
+        None => Ok((s.trim(), None)),
+        Some(a) => match s[a + 1..].trim_end().strip_suffix('"') {
+            Some(m) => match unescape(m) {
+                Ok(m) => Ok((s[..a].trim(), Some(m))),
+                Err(e) => Err(format!("syntax error: {}: {}", e, s)),
+            },
+            None => Err(format!("syntax error: unterminated message: {}", s)),
+        },
+    }
+}
+
+// Splits the first value operand, the name of a constant or a literal ending with its closing
+// parenthesis, from the following ones
+fn split_operand<'a>(s: &'a str, symbols: &Symbols) -> (&'a str, &'a str) {
+    let s = s.trim();
+    if let Some((name, rest)) = s.split_once(char::is_whitespace) {
+        if symbols.constants.contains_key(name) {
+            return (name, rest.trim());
+        }
+    }
+    match find_unquoted(s, |c, depth| c == ')' && depth == 0) {
+        Some(a) => (s[..a + 1].trim(), s[a + 1..].trim()),
+        None => (s, ""),
+    }
+}
+
+// `printf "<format>" <n>`: the number of values must be the one of the format placeholders
+fn parse_printf(s: &str) -> result::Result<Instruction, String> {
+    if !s.starts_with('"') {
+        return Err(format!("syntax error: missing format string: {}", s));
+    }
+    // Closing quote: the first one that is not escaped
+    let mut end = None;
+    let mut chars = s.char_indices().skip(1);
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => {
+                end = Some(offset - 1);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let (fmt, n) = match end {
+        None => return Err(format!("syntax error: unterminated format string: {}", s)),
+        Some(end) => (&s[1..end + 1], s[end + 2..].trim()),
+    };
+    let fmt = match unescape(fmt) {
+        Ok(fmt) => fmt,
+        Err(e) => return Err(format!("syntax error: {}: {}", e, s)),
+    };
+    let n = match n.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return Err(format!("syntax error: illegal value count: {}", n)),
+    };
+    match placeholders(&fmt) {
+        Ok(count) if count == n => Ok(Instruction::Printf(fmt, n)),
+        Ok(count) => Err(format!(
+            "printf: format string takes {} value(s), not {}: {}",
+            count, n, s
+        )),
+        Err(e) => Err(format!("syntax error: {}: {}", e, s)),
+    }
+}
+
+// Value operand: a literal, or with symbols the name of a constant or a numeric value holding a
+// constant expression
+pub fn parse_value(s: &str, symbols: &Symbols) -> result::Result<Value, String> {
+    if let Some(v) = symbols.constants.get(s) {
+        return Ok(v.clone());
+    }
     match Value::parse(s) {
+        Err(e) if symbols.expressions => match expr::evaluate(s, &symbols.constants)? {
+            Some(v) => Ok(v),
+            None => Err(e),
+        },
+        result => result,
+    }
+}
+
+// Code address operand: a label, or an instruction address
+fn parse_label(s: &str, symbols: &Symbols) -> result::Result<usize, String> {
+    match symbols.labels.get(s).or_else(|| symbols.later.get(s)) {
+        Some(addr) => Ok(*addr),
+        None => match s.parse::<usize>() {
+            Ok(addr) => Ok(addr),
+            Err(_) if symbols.any_label => Ok(0),
+            Err(_) => Err(format!("syntax error: unknown label: {}", s)),
+        },
+    }
+}
+
+// Operands of `native`: the name of the function, and its argument count
+fn parse_native(s: &str) -> result::Result<Instruction, String> {
+    let (name, n) = s.split_once(' ').unwrap_or((s, ""));
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match (valid, n.trim().parse::<usize>()) {
+        (true, Ok(n)) => Ok(Instruction::Native(String::from(name), n)),
+        _ => Err(format!("syntax error: expected native <name> <argument count>: {}", s)),
+    }
+}
+
+// Frame slot or frame size operand: a number, or a constant declared by `.const`, as slots are
+// often named, and so is their count
+fn parse_slot(s: &str, symbols: &Symbols, what: &str) -> result::Result<usize, String> {
+    let slot = match symbols.constants.get(s) {
+        Some(v) => v.as_integer().and_then(|i| usize::try_from(i).ok()),
+        None => s.parse::<usize>().ok(),
+    };
+    match slot {
+        Some(slot) => Ok(slot),
+        None => Err(format!("syntax error: illegal frame {}: {}", what, s)),
+    }
+}
+
+// Registers are numbered from 0, the VM having 16 of them by default (see `Config::registers`)
+pub const MAX_REGISTERS: usize = 256;
+
+// Register operand: `3`, `r3`, `int8(3)` or an alias declared by `.reg`
+pub fn parse_reg(s: &str, symbols: &Symbols) -> result::Result<usize, String> {
+    let number = match symbols.registers.get(s) {
+        Some(r) => return Ok(*r),
+        None if s.contains('(') => match Value::parse(s) {
+            Ok(Value::Int8(v)) => v as i64,
+            Ok(other) => return Err(format!("invalid value for register: {}", other)),
+            Err(e) => return Err(e),
+        },
+        None => match s.strip_prefix('r').unwrap_or(s).parse::<i64>() {
+            Ok(v) => v,
+            Err(_) => return Err(format!("syntax error: unknown register: {}", s)),
+        },
+    };
+    match usize::try_from(number) {
+        Ok(r) if r < MAX_REGISTERS => Ok(r),
+        _ => Err(format!("invalid register {}", s)),
+    }
+}
+
+// Exit code 84 is reserved for VM errors, so that a program can't be mistaken for a failing VM
+const VM_ERROR_EXIT_CODE: i8 = 84;
+
+// Whether a program may exit with the given code: a non-negative int8 other than the VM error one
+pub fn is_exit_code(code: u8) -> bool {
+    code <= i8::MAX as u8 && code != VM_ERROR_EXIT_CODE as u8
+}
+
+fn parse_exit_code(s: &str, symbols: &Symbols) -> result::Result<u8, String> {
+    if s.is_empty() {
+        return Ok(0);
+    }
+    match parse_value(s, symbols) {
         Ok(v) => match v {
             Value::Int8(v) => {
-                if v < 0 || v > 15 {
-                    return Err(format!("invalid register {}", s));
+                if v < 0 {
+                    return Err(format!("invalid exit code {}", s));
+                }
+                if v == VM_ERROR_EXIT_CODE {
+                    return Err(format!("exit code is reserved for VM errors: {}", s));
                 }
-                Ok(v as usize)
+                Ok(v as u8)
             }
-            other => Err(format!("invalid value for register: {}", other)),
+            other => Err(format!("invalid value for exit code: {}", other)),
         },
         Err(e) => Err(e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bigdecimal::BigDecimal;
+
+    use super::*;
+    use crate::program::parse_source;
+
+    // Minimal xorshift generator, so that the property tests are reproducible without extra crates
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn random_string(rng: &mut Rng) -> String {
+        const CHARS: &[char] = &[
+            'a', 'Z', '0', ' ', '"', '\'', '\\', '(', ')', ';', ',', '\n', '\t', 'é', '✓', '\u{7}',
+        ];
+        (0..rng.below(12))
+            .map(|_| CHARS[rng.below(CHARS.len() as u64) as usize])
+            .collect()
+    }
+
+    fn random_value(rng: &mut Rng) -> Value {
+        let kind = rng.below(11);
+        random_nested_value(rng, kind, 2)
+    }
+
+    // Value of the given kind, arrays and tuples holding values nested up to the given depth
+    fn random_nested_value(rng: &mut Rng, kind: u64, depth: u32) -> Value {
+        let kinds = match depth {
+            0 => 9,
+            _ => 11,
+        };
+        match kind % kinds {
+            10 => Value::Tuple(
+                (0..rng.below(4))
+                    .map(|_| {
+                        let kind = rng.below(11);
+                        random_nested_value(rng, kind, depth - 1)
+                    })
+                    .collect(),
+            ),
+            9 => {
+                // Elements of an array are all of the same kind
+                let kind = rng.below(11);
+                Value::Array(
+                    (0..rng.below(4))
+                        .map(|_| random_nested_value(rng, kind, depth - 1))
+                        .collect(),
+                )
+            }
+            8 => Value::Char(random_string(rng).chars().next().unwrap_or('x')),
+            7 => Value::Bool(rng.below(2) == 1),
+            6 => Value::String(random_string(rng)),
+            0 => Value::Int8(rng.next() as i8),
+            1 => Value::Int16(rng.next() as i16),
+            2 => Value::Int32(rng.next() as i32),
+            3 => Value::Float(f32::from_bits(rng.next() as u32)),
+            4 => Value::Double(f64::from_bits(rng.next())),
+            _ => Value::BigDecimal(BigDecimal::new(
+                (rng.next() as i64).into(),
+                rng.below(40) as i64 - 20,
+            )),
+        }
+    }
+
+    fn random_message(rng: &mut Rng) -> Option<String> {
+        const CHARS: &[u8] = b"abcXYZ019 _-()\"'.,;";
+        match rng.below(2) {
+            0 => None,
+            _ => Some(
+                (0..rng.below(20))
+                    .map(|_| CHARS[rng.below(CHARS.len() as u64) as usize] as char)
+                    .collect(),
+            ),
+        }
+    }
+
+    // Index of each instruction variant: being an exhaustive match, adding an instruction won't
+    // compile until it is given one, and the round-trip test fails until `random_instruction`
+    // generates it (see `covered`)
+    const VARIANTS: usize = 71;
+
+    fn variant(i: &Instruction) -> usize {
+        match i {
+            Instruction::Noop => 0,
+            Instruction::Push(_) => 1,
+            Instruction::Pop => 2,
+            Instruction::Dump => 3,
+            Instruction::Clear => 4,
+            Instruction::Dup => 5,
+            Instruction::Swap => 6,
+            Instruction::Assert(_) => 7,
+            Instruction::Add => 8,
+            Instruction::Sub => 9,
+            Instruction::Mul => 10,
+            Instruction::Div => 11,
+            Instruction::Mod => 12,
+            Instruction::Load(_) => 13,
+            Instruction::Store(_) => 14,
+            Instruction::Print => 15,
+            Instruction::Exit(_) => 16,
+            Instruction::AssertEq(_) => 17,
+            Instruction::AssertNe(_) => 18,
+            Instruction::AssertLt(_) => 19,
+            Instruction::AssertType(_, _) => 20,
+            Instruction::AssertDepth(_, _) => 21,
+            Instruction::AssertApprox(_, _, _) => 22,
+            Instruction::AssertUlps(_, _, _) => 23,
+            Instruction::Prints => 24,
+            Instruction::Concat => 25,
+            Instruction::Len => 26,
+            Instruction::CharAt => 27,
+            Instruction::ToStr => 28,
+            Instruction::Parse(_) => 29,
+            Instruction::Printv => 30,
+            Instruction::Printd => 31,
+            Instruction::Putc => 32,
+            Instruction::Printf(_, _) => 33,
+            Instruction::Alloc(_) => 34,
+            Instruction::HLoad => 35,
+            Instruction::HStore => 36,
+            Instruction::Free => 37,
+            Instruction::AGet => 38,
+            Instruction::ASet => 39,
+            Instruction::ALen => 40,
+            Instruction::APush => 41,
+            Instruction::Eq => 42,
+            Instruction::Ne => 43,
+            Instruction::Lt => 44,
+            Instruction::Le => 45,
+            Instruction::Gt => 46,
+            Instruction::Ge => 47,
+            Instruction::Cast(_) => 48,
+            Instruction::IsSet(_) => 49,
+            Instruction::RegClear => 50,
+            Instruction::Over => 51,
+            Instruction::Rot => 52,
+            Instruction::MinusRot => 53,
+            Instruction::Nip => 54,
+            Instruction::Tuck => 55,
+            Instruction::Pick(_) => 56,
+            Instruction::Roll(_) => 57,
+            Instruction::Drop(_) => 58,
+            Instruction::Depth => 59,
+            Instruction::Addr(_) => 60,
+            Instruction::Try(_) => 61,
+            Instruction::EndTry => 62,
+            Instruction::Throw => 63,
+            Instruction::Call(_) => 64,
+            Instruction::Ret => 65,
+            Instruction::Enter(_) => 66,
+            Instruction::Leave => 67,
+            Instruction::LLoad(_) => 68,
+            Instruction::LStore(_) => 69,
+            Instruction::Native(_, _) => 70,
+        }
+    }
+
+    fn random_instruction(rng: &mut Rng) -> Instruction {
+        match rng.below(VARIANTS as u64) {
+            0 => Instruction::Noop,
+            1 => Instruction::Push(random_value(rng)),
+            2 => Instruction::Pop,
+            3 => Instruction::Dump,
+            4 => Instruction::Clear,
+            5 => Instruction::Dup,
+            6 => Instruction::Swap,
+            7 => Instruction::Assert(random_value(rng)),
+            8 => Instruction::Add,
+            9 => Instruction::Sub,
+            10 => Instruction::Mul,
+            11 => Instruction::Div,
+            12 => Instruction::Mod,
+            13 => Instruction::Load(rng.below(MAX_REGISTERS as u64) as usize),
+            14 => Instruction::Store(rng.below(MAX_REGISTERS as u64) as usize),
+            15 => Instruction::Print,
+            16 => match rng.below(128) as u8 {
+                84 => Instruction::Exit(0),
+                code => Instruction::Exit(code),
+            },
+            17 => Instruction::AssertEq(random_message(rng)),
+            18 => Instruction::AssertNe(random_message(rng)),
+            19 => Instruction::AssertLt(random_message(rng)),
+            20 => Instruction::AssertType(random_value(rng).get_type(), random_message(rng)),
+            21 => Instruction::AssertDepth(rng.below(1000) as usize, random_message(rng)),
+            22 => Instruction::AssertApprox(
+                random_value(rng),
+                random_value(rng),
+                random_message(rng),
+            ),
+            23 => Instruction::AssertUlps(random_value(rng), rng.next() as u32, random_message(rng)),
+            24 => Instruction::Prints,
+            25 => Instruction::Concat,
+            26 => Instruction::Len,
+            27 => Instruction::CharAt,
+            28 => Instruction::ToStr,
+            29 => Instruction::Parse(random_value(rng).get_type()),
+            30 => Instruction::Printv,
+            31 => Instruction::Printd,
+            32 => Instruction::Putc,
+            33 => {
+                const SPECS: &[&str] = &["{}", "{:?}", "{:>8.2}", "{:08}", "{{", "}}"];
+                let fmt: String = (0..rng.below(4))
+                    .map(|_| {
+                        format!(
+                            "{}{}",
+                            random_string(rng).replace(['{', '}'], ""),
+                            SPECS[rng.below(SPECS.len() as u64) as usize]
+                        )
+                    })
+                    .collect();
+                let n = placeholders(&fmt).unwrap();
+                Instruction::Printf(fmt, n)
+            }
+            34 => Instruction::Alloc(rng.below(1000) as usize),
+            35 => Instruction::HLoad,
+            36 => Instruction::HStore,
+            37 => Instruction::Free,
+            38 => Instruction::AGet,
+            39 => Instruction::ASet,
+            40 => Instruction::ALen,
+            41 => Instruction::APush,
+            42 => Instruction::Eq,
+            43 => Instruction::Ne,
+            44 => Instruction::Lt,
+            45 => Instruction::Le,
+            46 => Instruction::Gt,
+            47 => Instruction::Ge,
+            48 => Instruction::Cast(random_value(rng).get_type()),
+            49 => Instruction::IsSet(rng.below(MAX_REGISTERS as u64) as usize),
+            50 => Instruction::RegClear,
+            51 => Instruction::Over,
+            52 => Instruction::Rot,
+            53 => Instruction::MinusRot,
+            54 => Instruction::Nip,
+            55 => Instruction::Tuck,
+            56 => Instruction::Pick(rng.below(100) as usize),
+            57 => Instruction::Roll(rng.below(100) as usize),
+            58 => Instruction::Drop(rng.below(100) as usize),
+            59 => Instruction::Depth,
+            60 => Instruction::Addr(rng.below(100) as usize),
+            61 => Instruction::Try(rng.below(1000) as usize),
+            62 => Instruction::EndTry,
+            63 => Instruction::Throw,
+            64 => Instruction::Call(rng.below(1000) as usize),
+            65 => Instruction::Ret,
+            66 => Instruction::Enter(rng.below(100) as usize),
+            67 => Instruction::Leave,
+            68 => Instruction::LLoad(rng.below(100) as usize),
+            69 => Instruction::LStore(rng.below(100) as usize),
+            70 => Instruction::Native(String::from("hash"), rng.below(10) as usize),
+            n => unreachable!("no instruction of index {}", n),
+        }
+    }
+
+    // NaN is never equal to itself, and its payload is not kept by formatting, so instructions
+    // having NaN operands are compared by their formatting instead
+    fn same(a: &Instruction, b: &Instruction) -> bool {
+        let text = format!("{:?}", a);
+        a == b || (text.contains("NaN") && text == format!("{:?}", b))
+    }
+
+    #[test]
+    fn parse_format_round_trip() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let mut covered = [false; VARIANTS];
+        for _ in 0..10000 {
+            let i = random_instruction(&mut rng);
+            covered[variant(&i)] = true;
+            let text = format!("{}", i);
+            match Instruction::parse(&text, &Symbols::default()) {
+                Ok(parsed) => assert!(same(&i, &parsed), "{} parsed as {}", text, parsed),
+                Err(e) => panic!("{} does not parse: {}", text, e),
+            }
+            // Also as a source line followed by a comment, which must not be confused with the
+            // `;` and quotes of string literals
+            match parse_source(&format!("{} ; comment", text)) {
+                Ok(source) => match &source[0].instructions[..] {
+                    [parsed] => assert!(same(&i, parsed), "{} parsed as {}", text, parsed),
+                    _ => panic!("{} parsed as no single instruction", text),
+                },
+                Err(e) => panic!("{} does not parse as a source line: {}", text, e),
+            }
+        }
+        assert!(covered.iter().all(|c| *c), "not every instruction was generated");
+    }
+}
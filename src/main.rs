@@ -1,34 +1,370 @@
 use std::env;
+use std::fs;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::result;
+use std::time::Duration;
 
-use program::compile_asm;
+use bytecode::{check_operands, load_bytecode, write_bytecode};
+use disasm::disassemble;
+use formatter::format_source;
+use instruction::MAX_REGISTERS;
+use native::Natives;
+use optimizer::optimize;
+use process::{Config, Limits};
+use program::{compile_asm, read_source, Data, LineTable, Program};
+use testrunner::run_tests;
+use verifier::{verify, Severity};
 use vm::VM;
 
+mod bytecode;
+mod disasm;
+mod expr;
+mod formatter;
 mod instruction;
+mod native;
+mod optimizer;
+mod printf;
 mod process;
 mod program;
+mod testrunner;
 mod value;
+mod verifier;
 mod vm;
 
-fn main() -> ExitCode {
-    let args: Vec<String> = env::args().collect();
-    let filename = match &args[..] {
-        [ _ , f ] => f.as_str(),
-        _ => {
-            eprintln!("Error: missing argument");
-            eprintln!("Synopsys: abstract_vm <file_name>");
-            return ExitCode::from(84);
+const SYNOPSIS: &str = "Synopsys: abstract_vm [-I <dir>]... [--verify] [--check] [--loose] [-O] [--max-stack <n>] [--max-instructions <n>] \
+                        [--max-digits <n>] [--max-heap <n>] [--registers <n>] [--timeout <seconds>] <file_name>
+          abstract_vm asm [-I <dir>]... [--no-debug] [-O] <file_name> [-o <output_file>]
+          abstract_vm disasm <file_name>
+          abstract_vm verify [-I <dir>]... [--loose] [--max-stack <n>] <file_name>
+          abstract_vm fmt [-I <dir>]... [--check] <file_name>...
+          abstract_vm test [--bless] <directory>";
+
+fn parse_limit(name: &str, arg: Option<&String>) -> result::Result<usize, String> {
+    match arg {
+        None => Err(format!("Error: missing value for {}", name)),
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(format!("Error: invalid value for {}: {}", name, v)),
+        },
+    }
+}
+
+// Directories given by `-I <dir>`, where included files are searched, and the other arguments
+fn parse_include_dirs(args: &[String]) -> result::Result<(Vec<PathBuf>, Vec<String>), String> {
+    let mut dirs = Vec::new();
+    let mut others = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => match args.next() {
+                None => return Err(String::from("Error: missing value for -I")),
+                Some(dir) => dirs.push(PathBuf::from(dir)),
+            },
+            _ => others.push(arg.clone()),
         }
-    };
+    }
+    Ok((dirs, others))
+}
+
+struct RunOptions<'a> {
+    config: Config,
+    verify: bool,
+    optimize: bool,
+    filename: &'a str,
+}
+
+fn parse_run_args(args: &[String]) -> result::Result<RunOptions<'_>, String> {
+    let mut limits = Limits::default();
+    let mut verify = false;
+    let mut check = false;
+    let mut loose = false;
+    let mut optimize = false;
+    let mut registers = Config::default().registers;
+    let mut filename = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verify" => verify = true,
+            "--registers" => match parse_limit(arg, args.next())? {
+                n if (1..=MAX_REGISTERS).contains(&n) => registers = n,
+                n => return Err(format!("Error: invalid value for {}: {}", arg, n)),
+            },
+            "--check" => check = true,
+            "--loose" => loose = true,
+            "-O" => optimize = true,
+            "--max-stack" => limits.max_stack_depth = parse_limit(arg, args.next())?,
+            "--max-instructions" => limits.max_instructions = parse_limit(arg, args.next())?,
+            "--max-heap" => limits.max_heap_cells = parse_limit(arg, args.next())?,
+            "--max-digits" => limits.max_bigdecimal_digits = parse_limit(arg, args.next())? as u64,
+            "--timeout" => {
+                limits.timeout = Some(Duration::from_secs(parse_limit(arg, args.next())? as u64))
+            }
+            f => match filename {
+                None => filename = Some(f),
+                Some(_) => return Err(format!("Error: unexpected argument: {}", f)),
+            },
+        }
+    }
+    match filename {
+        None => Err(String::from("Error: missing argument")),
+        Some(filename) => Ok(RunOptions {
+            config: Config {
+                limits,
+                registers,
+                check,
+                loose,
+            },
+            verify,
+            optimize,
+            filename,
+        }),
+    }
+}
+
+struct VerifyOptions<'a> {
+    config: Config,
+    filename: &'a str,
+}
 
-    let mut vm: VM = VM::new();
+// Options of the configuration the program would run with, which the verifier depends on
+fn parse_verify_args(args: &[String]) -> result::Result<VerifyOptions<'_>, String> {
+    let mut config = Config::default();
+    let mut filename = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--loose" => config.loose = true,
+            "--max-stack" => config.limits.max_stack_depth = parse_limit(arg, args.next())?,
+            f => match filename {
+                None => filename = Some(f),
+                Some(_) => return Err(format!("Error: unexpected argument: {}", f)),
+            },
+        }
+    }
+    match filename {
+        None => Err(String::from("Error: missing argument")),
+        Some(filename) => Ok(VerifyOptions { config, filename }),
+    }
+}
 
-    let ret_code = match compile_asm(filename) {
-        Ok(prog) => {
-            let pid = vm.load_program(prog);
-            vm.run_process(pid)
+struct AsmOptions<'a> {
+    debug: bool,
+    optimize: bool,
+    input: &'a str,
+    output: String,
+}
+
+fn parse_asm_args(args: &[String]) -> result::Result<AsmOptions<'_>, String> {
+    let mut debug = true;
+    let mut optimize = false;
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-debug" => debug = false,
+            "-O" => optimize = true,
+            "-o" => match args.next() {
+                None => return Err(String::from("Error: missing value for -o")),
+                Some(o) => output = Some(o.clone()),
+            },
+            f => match input {
+                None => input = Some(f),
+                Some(_) => return Err(format!("Error: unexpected argument: {}", f)),
+            },
+        }
+    }
+    match input {
+        None => Err(String::from("Error: missing argument")),
+        Some(input) => {
+            // Default output is the input file with the bytecode extension
+            let output = output.unwrap_or_else(|| {
+                let stem = input.strip_suffix(".avm").unwrap_or(input);
+                format!("{}.{}", stem, bytecode::EXTENSION)
+            });
+            Ok(AsmOptions {
+                debug,
+                optimize,
+                input,
+                output,
+            })
         }
+    }
+}
+
+// Compiled programs are recognized by their extension, other files are assembled on the fly
+fn load(
+    filename: &str,
+    search: &[PathBuf],
+) -> result::Result<(Program, Option<LineTable>, Data), String> {
+    if filename.ends_with(&format!(".{}", bytecode::EXTENSION)) {
+        load_bytecode(filename)
+    } else {
+        let (prog, lines, data) = compile_asm(filename, search)?;
+        Ok((prog, Some(lines), data))
+    }
+}
+
+// Prints the verifier diagnostics, and fails if any of them is an error
+fn check(
+    prog: &Program,
+    lines: Option<&LineTable>,
+    config: &Config,
+) -> result::Result<(), String> {
+    let mut errors = 0;
+    for diagnostic in verify(prog, lines, config) {
+        if let Severity::Error = diagnostic.severity {
+            errors += 1;
+        }
+        eprintln!("{}", diagnostic);
+    }
+    match errors {
+        0 => Ok(()),
+        n => Err(format!("verification failed: {} error(s)", n)),
+    }
+}
+
+fn run(options: RunOptions, search: &[PathBuf]) -> result::Result<ExitCode, String> {
+    let (mut prog, lines, data) = load(options.filename, search)?;
+    if options.verify {
+        check(&prog, lines.as_ref(), &options.config)?;
+    }
+
+    let mut vm: VM = VM::new(options.config);
+    vm.register("hash", native::hash).arity(1).pure();
+    vm.register("max", native::max).pure();
+    if options.optimize {
+        prog = optimize(prog, lines, vm.natives()).0;
+    }
+    let pid = vm.load_program(prog, data);
+    vm.run_process(pid)
+}
+
+fn asm(options: AsmOptions, search: &[PathBuf]) -> result::Result<ExitCode, String> {
+    let (mut prog, mut lines, data) = match compile_asm(options.input, search) {
+        Ok((prog, lines, data)) => (prog, Some(lines), data),
+        Err(e) => return Err(e),
+    };
+    if options.optimize {
+        // Native functions are registered on the VM running the program, unknown here
+        (prog, lines) = optimize(prog, lines, &Natives::default());
+    }
+    // Checked before the line table is dropped, to report the source line
+    check_operands(&prog, lines.as_ref())?;
+    if !options.debug {
+        lines = None;
+    }
+    match write_bytecode(&options.output, &prog, lines.as_ref(), &data) {
+        Ok(_) => Ok(ExitCode::SUCCESS),
         Err(e) => Err(e),
+    }
+}
+
+fn verify_file(options: VerifyOptions, search: &[PathBuf]) -> result::Result<ExitCode, String> {
+    let (prog, lines, _) = load(options.filename, search)?;
+    check(&prog, lines.as_ref(), &options.config)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn disasm(filename: &str) -> result::Result<ExitCode, String> {
+    let (prog, lines, data) = load_bytecode(filename)?;
+    print!("{}", disassemble(&prog, lines.as_ref(), &data));
+    Ok(ExitCode::SUCCESS)
+}
+
+// Rewrites each file in canonical form, or with `check` only reports the files that are not
+fn fmt(check: bool, filenames: &[String], search: &[PathBuf]) -> result::Result<ExitCode, String> {
+    let mut unformatted = false;
+    for filename in filenames {
+        let source = match read_source(filename, search) {
+            Ok(source) => source,
+            Err(e) => return Err(format!("{}: {}", filename, e)),
+        };
+        let formatted = format_source(&source);
+        if fs::read_to_string(filename).ok().as_ref() == Some(&formatted) {
+            continue;
+        }
+        if check {
+            println!("{}", filename);
+            unformatted = true;
+        } else if let Err(why) = fs::write(filename, formatted) {
+            return Err(format!("couldn't write {}: {}", filename, why));
+        }
+    }
+    match unformatted {
+        true => Ok(ExitCode::FAILURE),
+        false => Ok(ExitCode::SUCCESS),
+    }
+}
+
+fn test(blessing: bool, dir: &str) -> result::Result<ExitCode, String> {
+    match run_tests(dir, blessing)? {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE),
+    }
+}
+
+fn main() -> ExitCode {
+    let (search, args) = match parse_include_dirs(&env::args().collect::<Vec<String>>()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", SYNOPSIS);
+            return ExitCode::from(84);
+        }
+    };
+    let ret_code = match args.get(1).map(|s| s.as_str()) {
+        Some("asm") => match parse_asm_args(&args[2..]) {
+            Ok(options) => asm(options, &search),
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("{}", SYNOPSIS);
+                return ExitCode::from(84);
+            }
+        },
+        Some("disasm") => match &args[2..] {
+            [filename] => disasm(filename),
+            _ => {
+                eprintln!("Error: missing argument");
+                eprintln!("{}", SYNOPSIS);
+                return ExitCode::from(84);
+            }
+        },
+        Some("verify") => match parse_verify_args(&args[2..]) {
+            Ok(options) => verify_file(options, &search),
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("{}", SYNOPSIS);
+                return ExitCode::from(84);
+            }
+        },
+        Some("fmt") => match &args[2..] {
+            [] => {
+                eprintln!("Error: missing argument");
+                eprintln!("{}", SYNOPSIS);
+                return ExitCode::from(84);
+            }
+            [check, filenames @ ..] if check == "--check" => fmt(true, filenames, &search),
+            filenames => fmt(false, filenames, &search),
+        },
+        Some("test") => match &args[2..] {
+            [dir] => test(false, dir),
+            [bless, dir] if bless == "--bless" => test(true, dir),
+            _ => {
+                eprintln!("Error: missing argument");
+                eprintln!("{}", SYNOPSIS);
+                return ExitCode::from(84);
+            }
+        },
+        _ => match parse_run_args(&args[1..]) {
+            Ok(options) => run(options, &search),
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("{}", SYNOPSIS);
+                return ExitCode::from(84);
+            }
+        },
     };
 
     match ret_code {
@@ -0,0 +1,106 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::result;
+
+use crate::value::Value;
+
+// Function of the host, registered on the VM by its embedder and called by `native <name> <n>`
+// with the n values on top of the stack, in the order they were pushed. It returns the value to
+// push in their place, or an error message.
+pub type NativeFn = dyn Fn(&[Value]) -> result::Result<Value, String>;
+
+#[derive(Clone)]
+pub struct Native {
+    function: Rc<NativeFn>,
+    arity: Option<usize>, // number of arguments, any if not given
+    // Whether the function only depends on its arguments and has no side effect, so that the
+    // optimizer may call it ahead of execution
+    pure: bool,
+}
+
+impl Native {
+    // Checks the number of arguments of the calls
+    pub fn arity(&mut self, n: usize) -> &mut Native {
+        self.arity = Some(n);
+        self
+    }
+
+    pub fn pure(&mut self) -> &mut Native {
+        self.pure = true;
+        self
+    }
+
+    pub fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    // Checks the number of arguments of a call
+    pub fn check_arity(&self, name: &str, n: usize) -> result::Result<(), String> {
+        match self.arity {
+            Some(arity) if arity != n => Err(format!(
+                "native {} takes {} argument(s), not {}",
+                name, arity, n
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn call(&self, args: &[Value]) -> result::Result<Value, String> {
+        (self.function)(args)
+    }
+}
+
+// Native functions by name
+#[derive(Clone, Default)]
+pub struct Natives {
+    functions: HashMap<String, Native>,
+}
+
+impl Natives {
+    // Registers a function, replacing any other of the same name. Its arity and purity can be
+    // given on the returned entry.
+    pub fn register<F>(&mut self, name: &str, f: F) -> &mut Native
+    where
+        F: Fn(&[Value]) -> result::Result<Value, String> + 'static,
+    {
+        let native = Native {
+            function: Rc::new(f),
+            arity: None,
+            pure: false,
+        };
+        self.functions.insert(String::from(name), native);
+        self.functions.get_mut(name).unwrap()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Native> {
+        self.functions.get(name)
+    }
+}
+
+// Native functions of the command line VM, registered as an embedder would register its own
+
+// FNV-1a hash of the `Debug` formatting of a value, so that values of different types differ
+pub fn hash(args: &[Value]) -> result::Result<Value, String> {
+    let mut h: u32 = 0x811c9dc5;
+    for b in format!("{:?}", args[0]).bytes() {
+        h = (h ^ b as u32).wrapping_mul(0x01000193);
+    }
+    Ok(Value::Int32(h as i32))
+}
+
+// Greatest of the values, the first one if several are
+pub fn max(args: &[Value]) -> result::Result<Value, String> {
+    let mut max = match args.first() {
+        None => return Err(String::from("no values")),
+        Some(v) => v,
+    };
+    for v in &args[1..] {
+        match v.compare(max) {
+            Some(Ordering::Greater) => max = v,
+            Some(_) => {}
+            None => return Err(format!("values are not comparable: {:?} and {:?}", max, v)),
+        }
+    }
+    Ok(max.clone())
+}
@@ -0,0 +1,166 @@
+use crate::instruction::Instruction;
+use crate::native::Natives;
+use crate::program::{LineTable, Program};
+use crate::value::Value;
+use crate::verifier::stack_depths;
+
+// Result of a binary operator applied to the two values pushed before it, in the same operand
+// order as the VM (see `Process::run`), or `None` if evaluating it would raise an error
+fn fold(a: &Value, b: &Value, op: &Instruction) -> Option<Value> {
+    let (a, b) = (a.clone(), b.clone());
+    match op {
+        Instruction::Add => (b + a).ok(),
+        Instruction::Sub => (a - b).ok(),
+        Instruction::Mul => (b * a).ok(),
+        Instruction::Div => (a / b).ok(),
+        Instruction::Mod => (a % b).ok(),
+        _ => None,
+    }
+}
+
+// Result of a call of a pure native function, whose arguments are pushed by the instructions before
+// it, or `None` if the call would raise an error. `kept` tells whether an instruction before it is
+// kept as is by the pass, so that the pushes can be replaced.
+fn fold_native(
+    code: &[(Instruction, Option<usize>)],
+    addr: usize,
+    natives: &Natives,
+    kept: impl Fn(usize) -> bool,
+) -> Option<Value> {
+    let (name, n) = match &code[addr].0 {
+        Instruction::Native(name, n) if *n <= addr => (name, *n),
+        _ => return None,
+    };
+    let native = natives.get(name).filter(|native| native.is_pure())?;
+    native.check_arity(name, n).ok()?;
+    let mut args = Vec::new();
+    for (a, (i, _)) in code.iter().enumerate().take(addr).skip(addr - n) {
+        match i {
+            Instruction::Push(v) if kept(a) => args.push(v.clone()),
+            _ => return None,
+        }
+    }
+    native.call(&args).ok()
+}
+
+// Addresses jumped to, by `call` or as the handlers of `try` blocks
+fn targets(code: &[(Instruction, Option<usize>)]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for (i, _) in code {
+        if let Instruction::Try(addr) | Instruction::Call(addr) = i {
+            if let Some(t) = targets.get_mut(*addr) {
+                *t = true;
+            }
+        }
+    }
+    targets
+}
+
+// One peephole pass over the program, returning whether anything changed
+fn optimize_pass(code: &mut Vec<(Instruction, Option<usize>)>, natives: &Natives) -> bool {
+    let prog: Program = code.iter().map(|(i, _)| i.clone()).collect();
+    let depths = stack_depths(&prog);
+    let targets = targets(code);
+    let mut out = Vec::with_capacity(code.len());
+    // New address of each instruction, or of the one following it if it is removed
+    let mut moved = vec![0; code.len() + 1];
+    let mut changed = false;
+    let mut addr = 0;
+    while addr < code.len() {
+        moved[addr] = out.len();
+        if depths[addr].is_none() {
+            // Dead code, as after `exit`
+            changed = true;
+            addr += 1;
+            continue;
+        }
+        // The arguments of a native call must be pushed right before it, without a jump in between
+        let kept = |a: usize| moved[a] + (addr - a) == out.len() && !targets[a + 1];
+        if let Some(v) = fold_native(code, addr, natives, kept) {
+            // The result replaces the pushes, and takes the line of the call
+            let start = match &code[addr].0 {
+                Instruction::Native(_, n) => addr - n,
+                _ => addr,
+            };
+            out.truncate(out.len() - (addr - start));
+            moved[start..=addr].fill(out.len());
+            out.push((Instruction::Push(v), code[addr].1));
+            changed = true;
+            addr += 1;
+            continue;
+        }
+        // An instruction jumped to can't be merged with the ones before it
+        let window = (
+            &code[addr].0,
+            code.get(addr + 1).filter(|_| !targets[addr + 1]).map(|c| &c.0),
+            code.get(addr + 2).filter(|_| !targets[addr + 2]).map(|c| &c.0),
+        );
+        match window {
+            (Instruction::Noop, _, _) => addr += 1,
+            (Instruction::Push(_), Some(Instruction::Pop), _) => {
+                moved[addr + 1] = out.len();
+                addr += 2
+            }
+            // `dup` raises an underflow on an empty stack, so it can only go if it never is
+            (Instruction::Dup, Some(Instruction::Pop), _) if depths[addr] >= Some(1) => {
+                moved[addr + 1] = out.len();
+                addr += 2
+            }
+            (Instruction::Push(a), Some(Instruction::Push(b)), Some(op)) => {
+                match fold(a, b, op) {
+                    // The folded value takes the line of the operator, being its result
+                    Some(v) => {
+                        moved[addr + 1] = out.len();
+                        moved[addr + 2] = out.len();
+                        out.push((Instruction::Push(v), code[addr + 2].1));
+                        addr += 3;
+                    }
+                    None => {
+                        out.push(code[addr].clone());
+                        addr += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                out.push(code[addr].clone());
+                addr += 1;
+                continue;
+            }
+        }
+        changed = true;
+    }
+    moved[code.len()] = out.len();
+    for (i, _) in &mut out {
+        if let Instruction::Try(addr) | Instruction::Call(addr) = i {
+            *addr = moved.get(*addr).copied().unwrap_or(*addr);
+        }
+    }
+    *code = out;
+    changed
+}
+
+// Peephole optimizations and constant folding, applied until none applies anymore, the calls of
+// the pure functions among the given natives being folded as well. The observable behavior of the
+// program is kept, including the errors it raises: an operation that would raise one is never
+// folded, so that the VM still raises it at the same point.
+pub fn optimize(
+    prog: Program,
+    lines: Option<LineTable>,
+    natives: &Natives,
+) -> (Program, Option<LineTable>) {
+    let has_lines = lines.is_some();
+    let lines = lines.unwrap_or_default();
+    let mut code: Vec<(Instruction, Option<usize>)> = prog
+        .into_iter()
+        .enumerate()
+        .map(|(addr, i)| (i, lines.get(addr).copied()))
+        .collect();
+    while optimize_pass(&mut code, natives) {}
+    let (prog, lines): (Program, Vec<Option<usize>>) = code.into_iter().unzip();
+    let lines = match has_lines {
+        true => Some(lines.into_iter().map(|l| l.unwrap_or(0)).collect()),
+        false => None,
+    };
+    (prog, lines)
+}
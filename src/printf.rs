@@ -0,0 +1,187 @@
+use std::result;
+
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::{BigDecimal, Signed, Zero};
+
+use crate::value::{Type, Value};
+
+// Format strings of `printf`: text with `{}` placeholders, replaced by values in the order they were
+// pushed. A placeholder may give a specification, as in Rust: `{:<fill-less alignment><0><width>
+// .<precision>?}`, all parts being optional, `?` printing the value as `printd` does. Braces are
+// escaped by doubling them.
+
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+struct Spec {
+    align: Option<Align>,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    debug: bool,
+}
+
+enum Piece {
+    Text(String),
+    Value(Spec),
+}
+
+fn parse_spec(s: &str) -> result::Result<Spec, String> {
+    let mut spec = Spec {
+        align: None,
+        zero: false,
+        width: 0,
+        precision: None,
+        debug: false,
+    };
+    let mut s = match s.strip_prefix(':') {
+        Some(s) => s,
+        None if s.is_empty() => return Ok(spec),
+        None => return Err(format!("invalid format specification: {{{}}}", s)),
+    };
+    if let Some(rest) = s.strip_suffix('?') {
+        spec.debug = true;
+        s = rest;
+    }
+    match s.chars().next() {
+        Some('<') => spec.align = Some(Align::Left),
+        Some('>') => spec.align = Some(Align::Right),
+        Some('^') => spec.align = Some(Align::Center),
+        _ => {}
+    }
+    if spec.align.is_some() {
+        s = &s[1..];
+    }
+    if let Some(rest) = s.strip_prefix('0') {
+        spec.zero = true;
+        s = rest;
+    }
+    let (width, precision) = match s.find('.') {
+        Some(a) => (&s[..a], Some(&s[a + 1..])),
+        None => (s, None),
+    };
+    if !width.is_empty() {
+        match width.parse::<usize>() {
+            Ok(w) => spec.width = w,
+            Err(_) => return Err(format!("invalid format width: {}", width)),
+        }
+    }
+    if let Some(p) = precision {
+        match p.parse::<usize>() {
+            Ok(p) => spec.precision = Some(p),
+            Err(_) => return Err(format!("invalid format precision: {}", p)),
+        }
+    }
+    Ok(spec)
+}
+
+fn parse_format(fmt: &str) -> result::Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            ('{', _) => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Err(String::from("unmatched '{' in format string")),
+                    }
+                }
+                pieces.push(Piece::Text(text));
+                pieces.push(Piece::Value(parse_spec(&spec)?));
+                text = String::new();
+            }
+            ('}', _) => return Err(String::from("unmatched '}' in format string")),
+            _ => text.push(c),
+        }
+    }
+    pieces.push(Piece::Text(text));
+    Ok(pieces)
+}
+
+// Number of values a format string takes
+pub fn placeholders(fmt: &str) -> result::Result<usize, String> {
+    Ok(parse_format(fmt)?
+        .iter()
+        .filter(|p| matches!(p, Piece::Value(_)))
+        .count())
+}
+
+// Bigdecimal with the given number of fractional digits, rounded half to even as floats are
+fn round(v: &BigDecimal, precision: usize) -> BigDecimal {
+    let ulp = BigDecimal::new(BigInt::from(1), precision as i64);
+    // Truncated towards zero, the remainder having the sign of the value
+    let truncated = v.with_scale(precision as i64);
+    let twice = (v - &truncated).abs() * BigDecimal::from(2);
+    let odd = !(truncated.as_bigint_and_exponent().0 % BigInt::from(2)).is_zero();
+    match twice > ulp || (twice == ulp && odd) {
+        true if v.is_negative() => truncated - ulp,
+        true => truncated + ulp,
+        false => truncated,
+    }
+}
+
+fn format_value(v: &Value, spec: &Spec) -> String {
+    let text = match (spec.debug, spec.precision, v) {
+        (true, _, v) => format!("{:?}", v),
+        (false, Some(p), Value::Float(v)) => format!("{:.*}", p, v),
+        (false, Some(p), Value::Double(v)) => format!("{:.*}", p, v),
+        (false, Some(p), Value::BigDecimal(v)) => format!("{}", round(v, p)),
+        (false, Some(p), Value::String(v)) => v.chars().take(p).collect(),
+        (false, _, v) => format!("{}", v),
+    };
+    let len = text.chars().count();
+    if len >= spec.width {
+        return text;
+    }
+    let padding = spec.width - len;
+    // Zero padding goes between the sign and the digits of numbers, and is ignored for other values
+    if spec.zero && v.get_type() <= Type::BigDecimal && !spec.debug {
+        let (sign, digits) = match text.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", text.as_str()),
+        };
+        return format!("{}{}{}", sign, "0".repeat(padding), digits);
+    }
+    // Numbers are right-aligned by default, strings left-aligned, as in Rust
+    let align = spec.align.unwrap_or(match v {
+        Value::String(_) => Align::Left,
+        _ => Align::Right,
+    });
+    match align {
+        Align::Left => format!("{}{}", text, " ".repeat(padding)),
+        Align::Right => format!("{}{}", " ".repeat(padding), text),
+        Align::Center => format!(
+            "{}{}{}",
+            " ".repeat(padding / 2),
+            text,
+            " ".repeat(padding - padding / 2)
+        ),
+    }
+}
+
+pub fn format(fmt: &str, values: &[Value]) -> result::Result<String, String> {
+    let mut out = String::new();
+    let mut values = values.iter();
+    for piece in parse_format(fmt)? {
+        match piece {
+            Piece::Text(text) => out.push_str(&text),
+            Piece::Value(spec) => match values.next() {
+                Some(v) => out.push_str(&format_value(v, &spec)),
+                None => return Err(String::from("missing value for format string")),
+            },
+        }
+    }
+    Ok(out)
+}
@@ -1,166 +1,936 @@
-use std::cmp::min;
-use std::panic;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
 use std::result;
+use std::time::Duration;
 
 use crate::instruction::Instruction;
-use crate::program::Program;
-use crate::value::Value;
+use crate::native::Natives;
+use crate::printf::format;
+use crate::program::{Data, Program};
+use crate::value::{Arithmetic, ArithmeticError, Type, Value};
+
+// Resource limits enforced while running a process, so that a misbehaving program fails
+// cleanly instead of exhausting the host memory or CPU
+#[derive(Clone)]
+pub struct Limits {
+    pub max_stack_depth: usize, // also of the call depth and of the frame slots
+    pub max_instructions: usize,
+    pub max_bigdecimal_digits: u64,
+    pub max_heap_cells: usize, // of the blocks allocated and not freed
+    pub timeout: Option<Duration>, // enforced by the VM, as it is the one scheduling the process
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_stack_depth: 1 << 16,
+            max_instructions: usize::MAX,
+            max_bigdecimal_digits: 1 << 16,
+            max_heap_cells: 1 << 20,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub limits: Limits,
+    pub registers: usize, // number of registers, up to MAX_REGISTERS
+    // Check mode: assertion failures are counted and reported when the process exits, instead of
+    // aborting it
+    pub check: bool,
+    // Loose mode: assertions compare values of different types once promoted to their common
+    // type, and NaN equal to itself (see `Value::loose_eq`), instead of requiring the same type
+    pub loose: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            limits: Limits::default(),
+            registers: 16,
+            check: false,
+            loose: false,
+        }
+    }
+}
+
+impl Config {
+    // Equality of values for assertions, depending on the mode
+    fn equals(&self, v1: &Value, v2: &Value) -> bool {
+        match self.loose {
+            true => v1.loose_eq(v2),
+            false => v1 == v2,
+        }
+    }
+}
+
+// Kind of a runtime error. All but fatal ones can be caught by a `try` block, whose handler is
+// given the error code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    DivisionByZero,
+    Overflow,
+    StackUnderflow,
+    AssertionFailure,
+    Native, // raised by a native function
+    Thrown(Value), // by `throw`, the value being the error code
+    Fatal,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> Option<Value> {
+        match self {
+            ErrorKind::DivisionByZero => Some(Value::Int32(1)),
+            ErrorKind::Overflow => Some(Value::Int32(2)),
+            ErrorKind::StackUnderflow => Some(Value::Int32(3)),
+            ErrorKind::AssertionFailure => Some(Value::Int32(4)),
+            ErrorKind::Native => Some(Value::Int32(5)),
+            ErrorKind::Thrown(v) => Some(v.clone()),
+            ErrorKind::Fatal => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: String) -> RuntimeError {
+        RuntimeError { kind, message }
+    }
+}
+
+// Errors without a kind, as the limits being exceeded, are fatal
+impl From<String> for RuntimeError {
+    fn from(message: String) -> RuntimeError {
+        RuntimeError::new(ErrorKind::Fatal, message)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn underflow(name: &str) -> RuntimeError {
+    RuntimeError::new(ErrorKind::StackUnderflow, format!("stack underflow - {}", name))
+}
 
 pub struct Process {
-    program: Program,
+    program: Rc<Program>,
+    config: Config,
     state: State,
 }
 
+// Handler of a try block, and the state to restore when jumping to it: the stack depth, call count
+// and frame count at `try`
+struct Handler {
+    addr: usize,
+    depth: usize,
+    calls: usize,
+    frames: usize,
+}
+
 struct State {
     stack: Vec<Value>,
-    registers: [Option<Value>; 16],
+    registers: Vec<Option<Value>>,
+    // Blocks allocated by `alloc`, the handle of a block being its index + 1. Freed blocks are
+    // kept as None so that handles are never reused, and a use after free is always detected.
+    heap: Vec<Option<Vec<Option<Value>>>>,
+    data_blocks: usize, // first blocks of the heap, preloaded from the `.data` section
+    handlers: Vec<Handler>, // of the try blocks entered and not left, innermost last
+    // Calls not returned from, innermost last: return address, and frame count at `call`
+    calls: Vec<(usize, usize)>,
+    frames: Vec<Vec<Option<Value>>>, // entered by `enter` and not left, innermost last
     ip: usize,
-    exited: bool,
+    executed: usize,
+    exit_code: Option<u8>,
+    failed_assertions: Vec<String>,
+}
+
+impl State {
+    // Register of the given number, which the VM may not have as their count is configurable
+    fn register(&mut self, name: &str, r: usize) -> result::Result<&mut Option<Value>, String> {
+        let count = self.registers.len();
+        match self.registers.get_mut(r) {
+            Some(register) => Ok(register),
+            None => Err(format!("{}: no register r{} ({} registers)", name, r, count)),
+        }
+    }
+
+    // Heap block of a handle, for the given instruction
+    fn block(
+        &mut self,
+        name: &str,
+        handle: &Value,
+    ) -> result::Result<&mut Vec<Option<Value>>, String> {
+        let n = match handle.as_integer() {
+            Some(n) if n > 0 && (n as usize) <= self.heap.len() => n as usize,
+            _ => return Err(format!("{}: invalid heap handle: {:?}", name, handle)),
+        };
+        match &mut self.heap[n - 1] {
+            None => Err(format!("{}: use after free of heap handle {}", name, n)),
+            Some(block) => Ok(block),
+        }
+    }
+
+    // Cell of a heap block, at the given index, with bounds checking
+    fn cell(
+        &mut self,
+        name: &str,
+        handle: &Value,
+        index: &Value,
+    ) -> result::Result<&mut Option<Value>, String> {
+        let block = self.block(name, handle)?;
+        let len = block.len();
+        match index.as_integer().and_then(|i| usize::try_from(i).ok()) {
+            Some(i) if i < len => Ok(&mut block[i]),
+            _ => Err(format!("{}: index out of bounds: {:?} (size {})", name, index, len)),
+        }
+    }
+
+    // Top two values of the stack, the top one last
+    fn top_two(&self) -> Option<(&Value, &Value)> {
+        match self.stack.len() {
+            0 | 1 => None,
+            n => Some((&self.stack[n - 2], &self.stack[n - 1])),
+        }
+    }
+
+    // Outcome of an assertion, given its optional message and the failure description if it
+    // failed. In check mode, the failure is only recorded.
+    fn assertion(
+        &mut self,
+        check: bool,
+        message: &Option<String>,
+        failure: Option<String>,
+    ) -> result::Result<(), RuntimeError> {
+        let failure = match (failure, message) {
+            (None, _) => return Ok(()),
+            (Some(f), None) => format!("assertion failed: {}", f),
+            (Some(f), Some(m)) => format!("assertion failed: {}: {}", m, f),
+        };
+        match check {
+            true => {
+                self.failed_assertions.push(failure);
+                Ok(())
+            }
+            false => Err(RuntimeError::new(ErrorKind::AssertionFailure, failure)),
+        }
+    }
+
+    // Number of frames entered before the current call, which it can't access
+    fn caller_frames(&self) -> usize {
+        self.calls.last().map_or(0, |(_, frames)| *frames)
+    }
+
+    // Slot of the innermost frame, which must have been entered by the current call
+    fn slot(&mut self, name: &str, i: usize) -> result::Result<&mut Option<Value>, String> {
+        if self.frames.len() == self.caller_frames() {
+            return Err(format!("{}: no frame entered by the current call", name));
+        }
+        let frame = self.frames.last_mut().unwrap();
+        let len = frame.len();
+        match frame.get_mut(i) {
+            Some(slot) => Ok(slot),
+            None => Err(format!("{}: no slot {} ({} slots)", name, i, len)),
+        }
+    }
+
+    // Arithmetic operation of the given instruction on the top two values of the stack, the top
+    // one being the second operand
+    fn arithmetic(
+        &mut self,
+        name: &str,
+        op: fn(Value, Value) -> Arithmetic,
+    ) -> result::Result<(), RuntimeError> {
+        let (b, a) = match (self.stack.pop(), self.stack.pop()) {
+            (Some(b), Some(a)) => (b, a),
+            _ => return Err(underflow(name)),
+        };
+        match op(a, b) {
+            Ok(v) => self.stack.push(v),
+            Err(ArithmeticError::DivisionByZero) => {
+                let message = format!("division by zero - {}", name);
+                return Err(RuntimeError::new(ErrorKind::DivisionByZero, message));
+            }
+            Err(ArithmeticError::Overflow) => {
+                let message = format!("overflow - {}", name);
+                return Err(RuntimeError::new(ErrorKind::Overflow, message));
+            }
+            Err(e) => return Err(format!("{} - {}", e, name).into()),
+        }
+        Ok(())
+    }
+}
+
+// Character printed by `print` and `putc`: an int8 is taken as an ASCII code
+fn character(v: &Value) -> result::Result<char, String> {
+    match v {
+        Value::Char(c) => Ok(*c),
+        Value::Int8(c) => match u8::try_from(*c) {
+            Ok(c) => Ok(char::from(c)),
+            Err(_) => Err(format!("value is not ascii char: {}", c)),
+        },
+        v => Err(format!("value is not int8 or char: {:?}", v)),
+    }
+}
+
+// Index of an element of an array or tuple of the given length, with bounds checking
+fn element_index(name: &str, index: &Value, len: usize) -> result::Result<usize, String> {
+    match index.as_integer().and_then(|i| usize::try_from(i).ok()) {
+        Some(i) if i < len => Ok(i),
+        _ => Err(format!("{}: index out of bounds: {:?} (length {})", name, index, len)),
+    }
 }
 
 impl Process {
-    pub fn new(p: Program) -> Process {
+    pub fn new(p: Program, data: Data, config: Config) -> Process {
+        let registers = vec![None; config.registers];
+        let data_blocks = data.len();
+        let heap = data
+            .into_iter()
+            .map(|block| Some(block.into_iter().map(Some).collect()))
+            .collect();
         Process {
-            program: p,
+            program: Rc::new(p),
+            config,
             state: State {
                 stack: Vec::new(),
-                registers: [
-                    None, None, None, None, None, None, None, None, None, None, None, None, None,
-                    None, None, None,
-                ],
+                registers,
+                heap,
+                data_blocks,
+                handlers: Vec::new(),
+                calls: Vec::new(),
+                frames: Vec::new(),
                 ip: 0,
-                exited: false,
+                executed: 0,
+                exit_code: None,
+                failed_assertions: Vec::new(),
             },
         }
     }
 
-    pub fn run(&mut self, count: usize) -> result::Result<bool, String> {
+    // Runs at most count instructions, calling the given native functions. Returns whether the
+    // process is still running.
+    pub fn run(&mut self, count: usize, natives: &Natives) -> result::Result<bool, RuntimeError> {
         // Error checking
         if count == 0 {
-            return Err(format!("no cycles given for process to run"));
+            return Err(String::from("no cycles given for process to run").into());
         }
         if self.state.ip == self.program.len() {
-            return Err(format!(
-                "process reached end of program instructions without exiting"
-            ));
+            return Err(String::from(
+                "process reached end of program instructions without exiting",
+            )
+            .into());
         }
 
-        // Compute the instruction range to execute = from ip to min(program_len, ip+count)
-        let exec_instructions =
-            &self.program[self.state.ip..min(self.program.len(), self.state.ip + count)];
-
-        // Execute each instruction in the range
-        for instruction in exec_instructions {
-            if self.state.exited {
+        // Execute at most count instructions, until the process exits or reaches the end of the
+        // program
+        let program = Rc::clone(&self.program);
+        for _ in 0..count {
+            let instruction = match program.get(self.state.ip) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            if self.state.executed == self.config.limits.max_instructions {
                 return Err(format!(
-                    "process attempted to run an instruction past after having exited"
-                ));
-            }
-            match instruction {
-                Instruction::Noop => {}
-                Instruction::Push(v) => self.state.stack.push(v.clone()),
-                Instruction::Pop => match self.state.stack.pop() {
-                    None => return Err(format!("stack underflow - pop")),
-                    Some(_) => {} // already pop'ed in the match clause
-                },
-                Instruction::Dump => {
-                    for v in self.state.stack.iter().rev() {
-                        println!("{}", v);
-                    }
+                    "limit exceeded: instruction budget of {} exhausted",
+                    self.config.limits.max_instructions
+                )
+                .into());
+            }
+            self.state.executed += 1;
+            self.check_operands(instruction)?;
+            match self.execute(instruction, natives) {
+                Ok(next) => self.state.ip = next,
+                Err(e) => self.catch(e)?,
+            }
+            self.check_limits()?;
+            if self.state.exit_code.is_some() {
+                break;
+            }
+        }
+        Ok(self.state.exit_code.is_none())
+    }
+
+    // Executes an instruction, returning the address of the next one
+    fn execute(
+        &mut self,
+        instruction: &Instruction,
+        natives: &Natives,
+    ) -> result::Result<usize, RuntimeError> {
+        match instruction {
+            Instruction::Noop => {}
+            Instruction::Push(v) => self.state.stack.push(v.clone()),
+            Instruction::Pop => {
+                if self.state.stack.pop().is_none() {
+                    return Err(underflow("pop"));
                 }
-                Instruction::Clear => self.state.stack.clear(),
-                Instruction::Dup => match self.state.stack.pop() {
-                    None => return Err(format!("stack underflow - dup")),
-                    Some(v) => {
-                        self.state.stack.push(v.clone());
-                        self.state.stack.push(v);
-                    }
-                },
-                Instruction::Swap => match (self.state.stack.pop(), self.state.stack.pop()) {
-                    (Some(v1), Some(v2)) => {
-                        self.state.stack.push(v1);
-                        self.state.stack.push(v2);
+            }
+            Instruction::Dump => {
+                for v in self.state.stack.iter().rev() {
+                    println!("{}", v);
+                }
+            }
+            Instruction::Clear => self.state.stack.clear(),
+            Instruction::Dup => match self.state.stack.pop() {
+                None => return Err(underflow("dup")),
+                Some(v) => {
+                    self.state.stack.push(v.clone());
+                    self.state.stack.push(v);
+                }
+            },
+            Instruction::Swap => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(v1), Some(v2)) => {
+                    self.state.stack.push(v1);
+                    self.state.stack.push(v2);
+                }
+                _ => return Err(underflow("swap")),
+            },
+            Instruction::Over => match self.state.stack.len() {
+                0 | 1 => return Err(underflow("over")),
+                n => self.state.stack.push(self.state.stack[n - 2].clone()),
+            },
+            Instruction::Rot => match self.state.stack.len() {
+                0..=2 => return Err(underflow("rot")),
+                n => {
+                    let v = self.state.stack.remove(n - 3);
+                    self.state.stack.push(v);
+                }
+            },
+            Instruction::MinusRot => match self.state.stack.len() {
+                0..=2 => return Err(underflow("-rot")),
+                n => {
+                    let v = self.state.stack.remove(n - 1);
+                    self.state.stack.insert(n - 3, v);
+                }
+            },
+            Instruction::Nip => match self.state.stack.len() {
+                0 | 1 => return Err(underflow("nip")),
+                n => {
+                    self.state.stack.remove(n - 2);
+                }
+            },
+            Instruction::Tuck => match self.state.stack.len() {
+                0 | 1 => return Err(underflow("tuck")),
+                n => self.state.stack.insert(n - 2, self.state.stack[n - 1].clone()),
+            },
+            // `pick 0` is `dup`, `roll 1` is `swap` and `roll 2` is `rot`
+            Instruction::Pick(i) => match self.state.stack.len() {
+                n if n <= *i => return Err(underflow("pick")),
+                n => self.state.stack.push(self.state.stack[n - 1 - i].clone()),
+            },
+            Instruction::Roll(i) => match self.state.stack.len() {
+                n if n <= *i => return Err(underflow("roll")),
+                n => {
+                    let v = self.state.stack.remove(n - 1 - i);
+                    self.state.stack.push(v);
+                }
+            },
+            Instruction::Drop(i) => match self.state.stack.len() {
+                n if n < *i => return Err(underflow("drop")),
+                n => self.state.stack.truncate(n - i),
+            },
+            Instruction::Depth => match i32::try_from(self.state.stack.len()) {
+                Ok(n) => self.state.stack.push(Value::Int32(n)),
+                Err(_) => return Err(String::from("depth: stack too deep for int32").into()),
+            },
+            Instruction::Assert(v1) => {
+                let failure = match self.state.stack.last() {
+                    None => return Err(underflow("assert")),
+                    Some(v2) => match self.config.equals(v1, v2) {
+                        true => None,
+                        false => Some(format!("{:?} != {:?}", v1, v2)),
+                    },
+                };
+                self.state.assertion(self.config.check, &None, failure)?;
+            }
+            Instruction::AssertEq(m) => {
+                let failure = match self.state.top_two() {
+                    None => return Err(underflow("assert_eq")),
+                    Some((v2, v1)) => match self.config.equals(v2, v1) {
+                        true => None,
+                        false => Some(format!("{:?} != {:?}", v2, v1)),
+                    },
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::AssertNe(m) => {
+                let failure = match self.state.top_two() {
+                    None => return Err(underflow("assert_ne")),
+                    Some((v2, v1)) => match self.config.equals(v2, v1) {
+                        false => None,
+                        true => Some(format!("{:?} == {:?}", v2, v1)),
+                    },
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::AssertLt(m) => {
+                let failure = match self.state.top_two() {
+                    None => return Err(underflow("assert_lt")),
+                    Some((v2, v1)) => match v2.compare(v1) {
+                        Some(Ordering::Less) => None,
+                        Some(_) => Some(format!("{:?} >= {:?}", v2, v1)),
+                        None => Some(format!("{:?} and {:?} are not comparable", v2, v1)),
+                    },
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::AssertType(t, m) => {
+                let failure = match self.state.stack.last() {
+                    None => return Err(underflow("assert_type")),
+                    Some(v) => match v.get_type() == *t {
+                        true => None,
+                        false => Some(format!("{:?} is not {}", v, t)),
+                    },
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::AssertApprox(v1, e, m) => {
+                let failure = match self.state.stack.last() {
+                    None => return Err(underflow("assert_approx")),
+                    Some(v2) => match v2.approx_eq(v1, e) {
+                        true => None,
+                        false => Some(format!("{:?} is not within {:?} of {:?}", v2, e, v1)),
+                    },
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::AssertUlps(v1, n, m) => {
+                let failure = match self.state.stack.last() {
+                    None => return Err(underflow("assert_ulps")),
+                    Some(v2) => match v2.ulps_eq(v1, *n) {
+                        Some(true) => None,
+                        Some(false) => {
+                            Some(format!("{:?} is not within {} ulps of {:?}", v2, n, v1))
+                        }
+                        None => return Err(format!("assert_ulps: no ulps for {:?}", v2).into()),
+                    },
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::AssertDepth(n, m) => {
+                let depth = self.state.stack.len();
+                let failure = match depth == *n {
+                    true => None,
+                    false => Some(format!("stack depth is {}, not {}", depth, n)),
+                };
+                self.state.assertion(self.config.check, m, failure)?;
+            }
+            Instruction::Add => self.state.arithmetic("add", |a, b| b + a)?,
+            Instruction::Mul => self.state.arithmetic("mul", |a, b| b * a)?,
+            Instruction::Sub => self.state.arithmetic("sub", |a, b| a - b)?,
+            Instruction::Div => self.state.arithmetic("div", |a, b| a / b)?,
+            Instruction::Mod => self.state.arithmetic("mod", |a, b| a % b)?,
+            Instruction::Load(r) => match self.state.register("load", *r)? {
+                None => return Err(format!("load: register is empty: r{}", r).into()),
+                Some(v) => {
+                    let v = v.clone();
+                    self.state.stack.push(v);
+                }
+            },
+            Instruction::Store(r) => match self.state.stack.pop() {
+                None => return Err(underflow("store")),
+                val => *self.state.register("store", *r)? = val,
+            },
+            Instruction::IsSet(r) => {
+                let set = self.state.register("isset", *r)?.is_some();
+                self.state.stack.push(Value::Bool(set));
+            }
+            Instruction::RegClear => self.state.registers.fill(None),
+            Instruction::Print => match self.state.stack.last() {
+                None => return Err(underflow("print")),
+                Some(v) => println!("{}", character(v)?),
+            },
+            Instruction::Prints => match self.state.stack.last() {
+                None => return Err(underflow("prints")),
+                Some(Value::String(s)) => println!("{}", s),
+                Some(v) => return Err(format!("value is not string: {:?}", v).into()),
+            },
+            Instruction::Printv => match self.state.stack.last() {
+                None => return Err(underflow("printv")),
+                Some(v) => println!("{}", v),
+            },
+            Instruction::Printd => match self.state.stack.last() {
+                None => return Err(underflow("printd")),
+                Some(v) => println!("{:?}", v),
+            },
+            Instruction::Putc => match self.state.stack.last() {
+                None => return Err(underflow("putc")),
+                Some(v) => print!("{}", character(v)?),
+            },
+            Instruction::Printf(fmt, n) => {
+                let stack = &mut self.state.stack;
+                if stack.len() < *n {
+                    return Err(underflow("printf"));
+                }
+                // Values are formatted in the order they were pushed
+                let values = stack.split_off(stack.len() - n);
+                print!("{}", format(fmt, &values)?);
+            }
+            Instruction::Alloc(n) => {
+                let live: usize = self.state.heap.iter().flatten().map(|b| b.len()).sum();
+                // A size overflowing the count exceeds the limit all the same
+                if live.checked_add(*n).is_none_or(|c| c > self.config.limits.max_heap_cells) {
+                    return Err(format!(
+                        "limit exceeded: heap cell count > {}",
+                        self.config.limits.max_heap_cells
+                    )
+                    .into());
+                }
+                match i32::try_from(self.state.heap.len() + 1) {
+                    Ok(handle) => {
+                        self.state.heap.push(Some(vec![None; *n]));
+                        self.state.stack.push(Value::Int32(handle));
                     }
-                    _ => return Err(format!("stack underflow - swap")),
-                },
-                Instruction::Assert(v1) => match self.state.stack.last() {
-                    None => return Err(format!("stack underflow - assert")),
-                    Some(v2) => {
-                        if !(v1 == v2) {
-                            return Err(format!("assertion failed: {:?} != {:?}", v1, v2));
+                    Err(_) => return Err(String::from("alloc: out of heap handles").into()),
+                }
+            }
+            Instruction::HLoad => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(index), Some(handle)) => {
+                    match self.state.cell("hload", &handle, &index)? {
+                        Some(v) => {
+                            let v = v.clone();
+                            self.state.stack.push(v);
+                        }
+                        None => {
+                            return Err(format!(
+                                "hload: uninitialized heap cell: {:?}[{:?}]",
+                                handle, index
+                            )
+                            .into())
                         }
                     }
-                },
-                Instruction::Add => match (self.state.stack.pop(), self.state.stack.pop()) {
-                    (Some(v1), Some(v2)) => match panic::catch_unwind(|| v1 + v2) {
+                }
+                _ => return Err(underflow("hload")),
+            },
+            Instruction::HStore => match (
+                self.state.stack.pop(),
+                self.state.stack.pop(),
+                self.state.stack.pop(),
+            ) {
+                (Some(v), Some(index), Some(handle)) => {
+                    *self.state.cell("hstore", &handle, &index)? = Some(v)
+                }
+                _ => return Err(underflow("hstore")),
+            },
+            Instruction::Free => match self.state.stack.pop() {
+                None => return Err(underflow("free")),
+                Some(handle) => {
+                    self.state.block("free", &handle)?;
+                    let n = handle.as_integer().unwrap_or_default() as usize;
+                    if n <= self.state.data_blocks {
+                        return Err(format!("free: heap handle {} is a data block", n).into());
+                    }
+                    self.state.heap[n - 1] = None;
+                }
+            },
+            Instruction::Addr(n) => match n.checked_add(1).map(i32::try_from) {
+                Some(Ok(handle)) if *n < self.state.data_blocks => {
+                    self.state.stack.push(Value::Int32(handle))
+                }
+                _ => {
+                    return Err(format!(
+                        "addr: no data block {} ({} blocks)",
+                        n, self.state.data_blocks
+                    )
+                    .into())
+                }
+            },
+            Instruction::AGet => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(index), Some(Value::Array(v) | Value::Tuple(v))) => {
+                    let i = element_index("aget", &index, v.len())?;
+                    self.state.stack.push(v[i].clone());
+                }
+                (Some(_), Some(v)) => {
+                    return Err(format!("value is not array or tuple: {:?}", v).into())
+                }
+                _ => return Err(underflow("aget")),
+            },
+            Instruction::ASet => match (
+                self.state.stack.pop(),
+                self.state.stack.pop(),
+                self.state.stack.pop(),
+            ) {
+                (Some(element), Some(index), Some(Value::Tuple(mut v))) => {
+                    let i = element_index("aset", &index, v.len())?;
+                    v[i] = element;
+                    self.state.stack.push(Value::Tuple(v));
+                }
+                (Some(element), Some(index), Some(Value::Array(mut v))) => {
+                    let i = element_index("aset", &index, v.len())?;
+                    v[i] = element;
+                    match Value::array(v) {
                         Ok(v) => self.state.stack.push(v),
-                        Err(_) => {
-                            return Err(format!("Arithmetic panic (see panic message) - add"))
-                        } // This is useless to catch the cause, as it is an Any { ... } on arithmetic panics
-                    },
-                    _ => return Err(format!("stack underflow - add")),
+                        Err(e) => return Err(format!("aset: {}", e).into()),
+                    }
+                }
+                (Some(_), Some(_), Some(v)) => {
+                    return Err(format!("value is not array or tuple: {:?}", v).into())
+                }
+                _ => return Err(underflow("aset")),
+            },
+            Instruction::ALen => match self.state.stack.pop() {
+                None => return Err(underflow("alen")),
+                Some(Value::Array(v) | Value::Tuple(v)) => match i32::try_from(v.len()) {
+                    Ok(len) => self.state.stack.push(Value::Int32(len)),
+                    Err(_) => return Err(String::from("alen: array too long for int32").into()),
                 },
-                Instruction::Mul => match (self.state.stack.pop(), self.state.stack.pop()) {
-                    (Some(v1), Some(v2)) => match panic::catch_unwind(|| v1 * v2) {
+                Some(v) => return Err(format!("value is not array or tuple: {:?}", v).into()),
+            },
+            Instruction::APush => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(element), Some(Value::Array(mut v))) => {
+                    v.push(element);
+                    match Value::array(v) {
                         Ok(v) => self.state.stack.push(v),
-                        Err(_) => {
-                            return Err(format!("Arithmetic panic (see panic message) - mul"))
-                        }
-                    },
-                    _ => return Err(format!("stack underflow - mul")),
+                        Err(e) => return Err(format!("apush: {}", e).into()),
+                    }
+                }
+                (Some(_), Some(v)) => return Err(format!("value is not array: {:?}", v).into()),
+                _ => return Err(underflow("apush")),
+            },
+            Instruction::Eq
+            | Instruction::Ne
+            | Instruction::Lt
+            | Instruction::Le
+            | Instruction::Gt
+            | Instruction::Ge => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(v1), Some(v2)) => {
+                    // Values not comparable, as NaN, are neither equal nor ordered
+                    let ordering = v2.compare(&v1);
+                    let result = match instruction {
+                        Instruction::Eq => ordering == Some(Ordering::Equal),
+                        Instruction::Ne => ordering != Some(Ordering::Equal),
+                        Instruction::Lt => ordering == Some(Ordering::Less),
+                        Instruction::Le => ordering.is_some_and(|o| o != Ordering::Greater),
+                        Instruction::Gt => ordering == Some(Ordering::Greater),
+                        _ => ordering.is_some_and(|o| o != Ordering::Less),
+                    };
+                    self.state.stack.push(Value::Bool(result));
+                }
+                _ => return Err(underflow(&format!("{:?}", instruction))),
+            },
+            Instruction::Cast(t) => match self.state.stack.pop() {
+                None => return Err(underflow("cast")),
+                Some(v) => match v.cast(*t) {
+                    Ok(v) => self.state.stack.push(v),
+                    Err(e) => return Err(format!("cast: {}", e).into()),
                 },
-                Instruction::Sub => match (self.state.stack.pop(), self.state.stack.pop()) {
-                    (Some(v1), Some(v2)) => match panic::catch_unwind(|| v2 - v1) {
-                        Ok(v) => self.state.stack.push(v),
-                        Err(_) => {
-                            return Err(format!("Arithmetic panic (see panic message) - sub"))
-                        }
-                    },
-                    _ => return Err(format!("stack underflow - sub")),
+            },
+            Instruction::Concat => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(Value::String(s1)), Some(Value::String(s2))) => {
+                    self.state.stack.push(Value::String(s2 + &s1))
+                }
+                (Some(v1), Some(v2)) => {
+                    return Err(format!("concat: values are not strings: {:?}, {:?}", v2, v1).into())
+                }
+                _ => return Err(underflow("concat")),
+            },
+            Instruction::Len => match self.state.stack.pop() {
+                None => return Err(underflow("len")),
+                Some(Value::String(s)) => match i32::try_from(s.chars().count()) {
+                    Ok(len) => self.state.stack.push(Value::Int32(len)),
+                    Err(_) => return Err(String::from("len: string too long for int32").into()),
                 },
-                Instruction::Div => match (self.state.stack.pop(), self.state.stack.pop()) {
-                    (Some(v1), Some(v2)) => match panic::catch_unwind(|| v2 / v1) {
-                        Ok(v) => self.state.stack.push(v),
-                        Err(_) => {
-                            return Err(format!("Arithmetic panic (see panic message) - div"))
+                Some(v) => return Err(format!("value is not string: {:?}", v).into()),
+            },
+            Instruction::CharAt => match (self.state.stack.pop(), self.state.stack.pop()) {
+                (Some(v1), Some(Value::String(s))) => match v1.as_integer() {
+                    None => return Err(format!("charat: index is not an integer: {:?}", v1).into()),
+                    Some(i) => match usize::try_from(i).ok().and_then(|i| s.chars().nth(i)) {
+                        Some(c) => self.state.stack.push(Value::Char(c)),
+                        None => {
+                            return Err(format!(
+                                "charat: index out of bounds: {} (length {})",
+                                i,
+                                s.chars().count()
+                            )
+                            .into())
                         }
                     },
-                    _ => return Err(format!("stack underflow - div")),
                 },
-                Instruction::Mod => match (self.state.stack.pop(), self.state.stack.pop()) {
-                    (Some(v1), Some(v2)) => match panic::catch_unwind(|| v2 % v1) {
+                (Some(_), Some(v2)) => return Err(format!("value is not string: {:?}", v2).into()),
+                _ => return Err(underflow("charat")),
+            },
+            Instruction::ToStr => match self.state.stack.pop() {
+                None => return Err(underflow("tostr")),
+                Some(v) => self.state.stack.push(Value::String(format!("{}", v))),
+            },
+            Instruction::Parse(t) => match self.state.stack.pop() {
+                None => return Err(underflow("parse")),
+                Some(Value::String(s)) => match t {
+                    Type::String => self.state.stack.push(Value::String(s)),
+                    t => match Value::parse(&format!("{}({})", t, s.trim())) {
                         Ok(v) => self.state.stack.push(v),
-                        Err(_) => {
-                            return Err(format!("Arithmetic panic (see panic message) - mod"))
-                        }
+                        Err(e) => return Err(format!("parse: {}", e).into()),
                     },
-                    _ => return Err(format!("stack underflow - mod")),
-                },
-                Instruction::Load(v) => match &self.state.registers[*v] {
-                    None => return Err(format!("load: register is empty: {}", v)),
-                    Some(v) => self.state.stack.push(v.clone()),
                 },
-                Instruction::Store(v) => match self.state.stack.pop() {
-                    None => return Err(format!("stack underflow - store")),
-                    val => self.state.registers[*v] = val,
-                },
-                Instruction::Print => match self.state.stack.last() {
-                    None => return Err(format!("stack underflow - print")),
-                    Some(v) => match v {
-                        Value::Int8(v) => {
-                            let c = *v as u8;
-                            match c.is_ascii() {
-                                false => return Err(format!("value is not ascii char: {}", c)),
-                                true => println!("{}", char::from(c)),
-                            }
-                        }
-                        _ => return Err(format!("value is not int8: {:?}", v)),
-                    },
-                },
-                Instruction::Exit => self.state.exited = true,
+                Some(v) => return Err(format!("value is not string: {:?}", v).into()),
+            },
+            Instruction::Exit(code) => self.state.exit_code = Some(*code),
+            // The handler is jumped to by `catch`, on an error raised before the matching `endtry`
+            Instruction::Try(addr) => self.state.handlers.push(Handler {
+                addr: *addr,
+                depth: self.state.stack.len(),
+                calls: self.state.calls.len(),
+                frames: self.state.frames.len(),
+            }),
+            Instruction::EndTry => {
+                if self.state.handlers.pop().is_none() {
+                    return Err(String::from("endtry: no try block").into());
+                }
+            }
+            Instruction::Throw => match self.state.stack.pop() {
+                None => return Err(underflow("throw")),
+                Some(v) => {
+                    let message = format!("uncaught exception: {:?}", v);
+                    return Err(RuntimeError::new(ErrorKind::Thrown(v), message));
+                }
+            },
+            Instruction::Call(addr) => {
+                let frames = self.state.frames.len();
+                self.state.calls.push((self.state.ip + 1, frames));
+                return Ok(*addr);
+            }
+            // The frames the routine did not leave are dropped, the caller's one being back
+            Instruction::Ret => match self.state.calls.pop() {
+                None => return Err(String::from("ret: no call to return from").into()),
+                Some((addr, frames)) => {
+                    self.state.frames.truncate(frames);
+                    return Ok(addr);
+                }
+            },
+            Instruction::Enter(n) => {
+                let slots: usize = self.state.frames.iter().map(|f| f.len()).sum();
+                if slots.checked_add(*n).is_none_or(|c| c > self.config.limits.max_stack_depth) {
+                    return Err(format!(
+                        "limit exceeded: frame slot count > {}",
+                        self.config.limits.max_stack_depth
+                    )
+                    .into());
+                }
+                self.state.frames.push(vec![None; *n]);
+            }
+            Instruction::Leave => {
+                if self.state.frames.len() == self.state.caller_frames() {
+                    return Err(String::from("leave: no frame entered by the current call").into());
+                }
+                self.state.frames.pop();
             }
-            self.state.ip = self.state.ip + 1;
+            Instruction::LLoad(i) => match self.state.slot("lload", *i)? {
+                None => return Err(format!("lload: slot is empty: {}", i).into()),
+                Some(v) => {
+                    let v = v.clone();
+                    self.state.stack.push(v);
+                }
+            },
+            Instruction::LStore(i) => match self.state.stack.pop() {
+                None => return Err(underflow("lstore")),
+                val => *self.state.slot("lstore", *i)? = val,
+            },
+            Instruction::Native(name, n) => {
+                let native = match natives.get(name) {
+                    None => return Err(format!("native: unknown function: {}", name).into()),
+                    Some(native) => native,
+                };
+                native.check_arity(name, *n)?;
+                let stack = &mut self.state.stack;
+                if stack.len() < *n {
+                    return Err(underflow("native"));
+                }
+                // Arguments are given in the order they were pushed
+                let args = stack.split_off(stack.len() - n);
+                match native.call(&args) {
+                    Ok(v) => self.state.stack.push(v),
+                    Err(e) => {
+                        let message = format!("native {}: {}", name, e);
+                        return Err(RuntimeError::new(ErrorKind::Native, message));
+                    }
+                }
+            }
+        }
+        Ok(self.state.ip + 1)
+    }
+
+    // Jumps to the handler of the innermost try block, if any and if the error can be caught,
+    // giving it the code of the error on the stack as it was at `try`
+    fn catch(&mut self, e: RuntimeError) -> result::Result<(), RuntimeError> {
+        match (e.kind.code(), self.state.handlers.pop()) {
+            (Some(code), Some(handler)) => {
+                self.state.stack.truncate(handler.depth);
+                self.state.stack.push(code);
+                self.state.calls.truncate(handler.calls);
+                self.state.frames.truncate(handler.frames);
+                self.state.ip = handler.addr;
+                Ok(())
+            }
+            _ => Err(e),
         }
-        Ok(!self.state.exited)
+    }
+
+    // Only the top of the stack may have been created by the last executed instruction, so this is
+    // the only value to check
+    fn check_limits(&self) -> result::Result<(), String> {
+        if self.state.stack.len() > self.config.limits.max_stack_depth {
+            return Err(format!(
+                "limit exceeded: stack depth > {}",
+                self.config.limits.max_stack_depth
+            ));
+        }
+        if self.state.calls.len() > self.config.limits.max_stack_depth {
+            return Err(format!(
+                "limit exceeded: call depth > {}",
+                self.config.limits.max_stack_depth
+            ));
+        }
+        self.check_digits(self.state.stack.last().into_iter())
+    }
+
+    // Operands of arithmetic are checked before the operation too, which could take unbounded time
+    // to produce a result over the limit
+    fn check_operands(&self, instruction: &Instruction) -> result::Result<(), String> {
+        match instruction {
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod => self.check_digits(self.state.stack.iter().rev().take(2)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_digits<'a>(
+        &self,
+        mut values: impl Iterator<Item = &'a Value>,
+    ) -> result::Result<(), String> {
+        let max = self.config.limits.max_bigdecimal_digits;
+        match values.any(|v| v.digits() > max) {
+            true => Err(format!("limit exceeded: bigdecimal digit count > {}", max)),
+            false => Ok(()),
+        }
+    }
+
+    // Assertion failures recorded in check mode
+    pub fn failed_assertions(&self) -> &[String] {
+        &self.state.failed_assertions
+    }
+
+    // Handles of the heap blocks allocated and not freed, data blocks being never freed
+    pub fn leaked_blocks(&self) -> Vec<usize> {
+        (self.state.data_blocks + 1..=self.state.heap.len())
+            .filter(|n| self.state.heap[n - 1].is_some())
+            .collect()
+    }
+
+    // Exit code given to the `exit` instruction, 0 if the process did not exit (yet)
+    pub fn exit_code(&self) -> u8 {
+        self.state.exit_code.unwrap_or(0)
     }
 }
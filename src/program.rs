@@ -1,36 +1,594 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::result;
 
-use crate::instruction::Instruction;
+use crate::instruction::{parse_reg, parse_value, Instruction, Symbols};
+use crate::value::{split_list, Type, Value};
 
 pub type Program = Vec<Instruction>;
 
-pub fn compile_asm(filename: &str) -> result::Result<Program, String> {
-    match File::open(&filename) {
-        Err(why) => return Err(format!("couldn't open {}: {}", filename, why)),
-        Ok(file) => {
-            let mut prog = Program::new();
-            for (_, line) in BufReader::new(file).lines().enumerate() {
-                match line {
-                    Err(why) => return Err(format!("couldn't read line: {}", why)),
-                    Ok(line) => {
-                        let line = match line.find(';') {
-                            Some(a) => String::from(&line[..a]),
-                            None => line,
-                        }
-                        .trim()
-                        .replace("\t", " ");
-                        if line.len() > 0 {
-                            match Instruction::parse(line.as_str()) {
-                                Ok(v) => prog.push(v),
-                                Err(v) => return Err(v),
-                            }
-                        }
+// Blocks declared by the `.data` section of a program, preloaded in the heap of its processes
+pub type Data = Vec<Vec<Value>>;
+
+// Source line number (1-based) of each instruction of a program, for debugging purpose
+pub type LineTable = Vec<usize>;
+
+// A line of assembly source, as written: blank lines and comments are kept so that the source can
+// be written back without loss (see `abstractvm fmt`)
+pub struct SourceLine {
+    pub instructions: Vec<Instruction>, // several for a macro call, none for a directive
+    // Canonical text of the instruction or directive, if any: the `Debug` formatting of the
+    // instruction, unless it uses symbols, which are kept as written
+    pub code: Option<String>,
+    pub comment: Option<String>, // text after the ';', if any
+}
+
+// Offset of the `;` starting the comment of a line, if any, ignoring the ones in string and char
+// literals
+pub fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut chars = line.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('\\', Some(_)) => {
+                chars.next(); // escaped character, possibly a quote
+            }
+            (';', None) => return Some(offset),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_identifier(s: &str) -> bool {
+    match s.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Assembler directive, declaring symbols for the following lines. Returns its canonical text.
+fn parse_directive(s: &str, symbols: &mut Symbols) -> result::Result<String, String> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    match (words[0].to_ascii_lowercase().as_str(), &words[1..]) {
+        (".reg", [name, r]) => {
+            // Names that are register operands by themselves, as `r3`, can't be aliases
+            if !is_identifier(name) || parse_reg(name, &Symbols::default()).is_ok() {
+                return Err(format!("syntax error: invalid register alias: {}", name));
+            }
+            if symbols.declares(name) {
+                return Err(format!("name already declared: {}", name));
+            }
+            let r = parse_reg(r, symbols)?;
+            symbols.registers.insert(String::from(*name), r);
+            Ok(format!(".reg {} r{}", name, r))
+        }
+        (".reg", _) => Err(format!("syntax error: expected .reg <name> <register>: {}", s)),
+        (".const", [name, "=", ..]) => {
+            if !is_identifier(name) {
+                return Err(format!("syntax error: invalid constant name: {}", name));
+            }
+            if symbols.declares(name) {
+                return Err(format!("name already declared: {}", name));
+            }
+            // The value is kept as written, as it may be an expression using other constants
+            let value = s[s.find('=').unwrap() + 1..].trim();
+            symbols.constants.insert(String::from(*name), parse_value(value, symbols)?);
+            Ok(format!(".const {} = {}", name, value))
+        }
+        (".const", _) => Err(format!("syntax error: expected .const <name> = <value>: {}", s)),
+        _ => Err(format!("syntax error: unknown directive: {}", s)),
+    }
+}
+
+// Macro defined by `.macro <name> <parameters>`, its body being the following lines up to `.endm`.
+// The body is expanded in the scope of its definition: the macros and register aliases declared
+// before it in its file, so that a macro exported by an included file works the same anywhere else.
+// Its labels are hygienic: each call has its own, hiding the labels of the file.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(usize, String)>, // line number and code of each line
+    file: Option<String>,       // file defining the macro, if included
+    macros: HashMap<String, Rc<Macro>>,
+    symbols: Symbols,
+}
+
+impl Macro {
+    // Instructions of a call of the macro at the given address, with the given arguments. The
+    // labels of the body are local to the call: a first pass collects their addresses, taking the
+    // unknown ones as address 0.
+    fn expand(
+        &self,
+        name: &str,
+        args: &str,
+        address: usize,
+    ) -> result::Result<Vec<Instruction>, String> {
+        let args = split_list(args);
+        if args.len() != self.params.len() {
+            return Err(format!(
+                "macro {} takes {} argument(s), not {}",
+                name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+        let mut first = self.symbols.clone();
+        first.any_label = true;
+        let (_, labels) = self.body(name, &args, address, first)?;
+        let mut symbols = self.symbols.clone();
+        symbols.labels.extend(labels);
+        Ok(self.body(name, &args, address, symbols)?.0)
+    }
+
+    // Instructions of the body at the given address, and the addresses of its labels
+    fn body(
+        &self,
+        name: &str,
+        args: &[&str],
+        address: usize,
+        // Aliases declared by the body are local to the call
+        mut symbols: Symbols,
+    ) -> result::Result<(Vec<Instruction>, HashMap<String, usize>), String> {
+        let mut instructions = Vec::new();
+        let mut labels = HashMap::new();
+        for (number, line) in &self.body {
+            let location = match &self.file {
+                Some(file) => format!("line {} of {}", number, file),
+                None => format!("line {}", number),
+            };
+            let code = match substitute(line, &self.params, args) {
+                Ok(code) => code,
+                Err(e) => return Err(format!("in macro {} at {}: {}", name, location, e)),
+            };
+            let (word, args) = match code.split_once(' ') {
+                Some((word, args)) => (word, args.trim()),
+                None => (code.as_str(), ""),
+            };
+            let at = address + instructions.len();
+            let expanded = match self.macros.get(word) {
+                Some(m) => m.expand(word, args, at),
+                None if word.ends_with(':') && code == word => {
+                    let label = &word[..word.len() - 1];
+                    if !is_identifier(label) || Instruction::is_opcode(label) {
+                        Err(format!("syntax error: invalid label: {}", label))
+                    } else if labels.insert(String::from(label), at).is_some() {
+                        Err(format!("name already declared: {}", label))
+                    } else {
+                        Ok(vec![])
                     }
+                }
+                None if word.starts_with('.') => match word.to_ascii_lowercase().as_str() {
+                    ".reg" => parse_directive(&code, &mut symbols).map(|_| vec![]),
+                    _ => Err(format!("unexpected {} in macro body", word)),
+                },
+                None => Instruction::parse(&code, &symbols).map(|i| vec![i]),
+            };
+            match expanded {
+                Ok(expanded) => instructions.extend(expanded),
+                Err(e) => return Err(format!("in macro {} at {}: {}", name, location, e)),
+            }
+        }
+        Ok((instructions, labels))
+    }
+}
+
+// Substitutes the `\<parameter>` of a line of a macro body (outside of string and char literals,
+// where `\` escapes characters) with the arguments of a call
+fn substitute(code: &str, params: &[String], args: &[&str]) -> result::Result<String, String> {
+    let mut out = String::new();
+    let mut quote = None;
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', None) => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    name.push(c);
+                }
+                match params.iter().position(|p| *p == name) {
+                    Some(i) => out.push_str(args[i]),
+                    None => return Err(format!("unknown macro parameter: \\{}", name)),
+                }
+                continue;
+            }
+            ('\\', Some(_)) => {
+                out.push(c);
+                if let Some(c) = chars.next() {
+                    out.push(c); // escaped character, possibly a quote
+                }
+                continue;
+            }
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+        out.push(c);
+    }
+    Ok(out)
+}
+
+// Path of a file given by `.include "<path>"`: relative to the directory of the including file, or
+// else to one of the search directories
+fn resolve_include(
+    path: &str,
+    file: Option<&Path>,
+    search: &[PathBuf],
+) -> result::Result<PathBuf, String> {
+    let dir = match file.and_then(|f| f.parent()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    match std::iter::once(&dir).chain(search).map(|d| d.join(path)).find(|p| p.is_file()) {
+        Some(p) => Ok(p),
+        None => Err(format!("include file not found: {}", path)),
+    }
+}
+
+// State of the assembler along the lines of a file: the symbols and macros declared so far
+struct Assembler<'a> {
+    file: Option<&'a Path>,
+    included: Option<String>, // path of the file as given by `.include`, unless it's the main one
+    search: &'a [PathBuf],    // directories where included files are searched
+    including: Vec<PathBuf>,  // this file and the ones including it, by canonical path
+    symbols: Symbols,
+    macros: HashMap<String, Rc<Macro>>,
+    defining: Option<(String, Macro)>, // macro whose body is being read, until `.endm`
+    exports: Vec<(usize, String)>,     // line number and name of each `.export`
+    in_data: bool,                     // whether the lines are in the `.data` section
+    data: Data,                        // blocks declared so far, including in other files
+    address: usize,                    // of the next instruction, including in other files
+}
+
+impl<'a> Assembler<'a> {
+    fn new(file: Option<&'a Path>, search: &'a [PathBuf], including: Vec<PathBuf>) -> Self {
+        Assembler {
+            file,
+            included: None,
+            search,
+            including,
+            symbols: Symbols {
+                expressions: true,
+                ..Symbols::default()
+            },
+            macros: HashMap::new(),
+            defining: None,
+            exports: Vec::new(),
+            in_data: false,
+            data: Data::new(),
+            address: 0,
+        }
+    }
+
+    // Assembler in the same initial state, for a first pass over the same file
+    fn fork(&self) -> Assembler<'a> {
+        Assembler {
+            included: self.included.clone(),
+            symbols: self.symbols.clone(),
+            macros: self.macros.clone(),
+            data: self.data.clone(),
+            address: self.address,
+            ..Assembler::new(self.file, self.search, self.including.clone())
+        }
+    }
+
+    // Instructions of a line of source, with the canonical text of its code
+    fn line(
+        &mut self,
+        number: usize,
+        code: &str,
+    ) -> result::Result<(Vec<Instruction>, Option<String>), String> {
+        let word = code.split_whitespace().next().unwrap_or("");
+        if let Some((name, m)) = &mut self.defining {
+            return match word.to_ascii_lowercase().as_str() {
+                "" => Ok((vec![], None)),
+                ".endm" => {
+                    let (name, m) = self.defining.take().unwrap();
+                    self.macros.insert(name, Rc::new(m));
+                    Ok((vec![], Some(String::from(".endm"))))
+                }
+                ".macro" => Err(format!("nested macro definition in macro {}", name)),
+                _ => {
+                    m.body.push((number, String::from(code)));
+                    Ok((vec![], Some(format!("    {}", written(code)))))
+                }
+            };
+        }
+        if word.ends_with(':') {
+            return match self.in_data {
+                true => Ok((vec![], Some(self.declare(code)?))),
+                false if code == word => Ok((vec![], Some(self.label(word)?))),
+                false => Err(format!("data declaration out of the .data section: {}", code)),
+            };
+        }
+        if self.in_data && !word.is_empty() && !word.starts_with('.') {
+            return Err(format!("instruction in the .data section: {}", code));
+        }
+        match word.to_ascii_lowercase().as_str() {
+            "" => Ok((vec![], None)),
+            ".data" | ".text" if code == word => {
+                self.in_data = code.eq_ignore_ascii_case(".data");
+                Ok((vec![], Some(code.to_ascii_lowercase())))
+            }
+            ".macro" => {
+                let (name, params) = self.define(code)?;
+                let text = match params.is_empty() {
+                    true => format!(".macro {}", name),
+                    false => format!(".macro {} {}", name, params.join(", ")),
+                };
+                let m = Macro {
+                    params,
+                    body: Vec::new(),
+                    file: self.included.clone(),
+                    macros: self.macros.clone(),
+                    symbols: self.symbols.clone(),
                 };
+                self.defining = Some((name, m));
+                Ok((vec![], Some(text)))
+            }
+            ".include" => {
+                let path = match code[word.len()..].trim() {
+                    p if p.len() > 2 && p.starts_with('"') && p.ends_with('"') => {
+                        &p[1..p.len() - 1]
+                    }
+                    _ => {
+                        return Err(format!("syntax error: expected .include \"<file>\": {}", code))
+                    }
+                };
+                Ok((self.include(path)?, Some(format!(".include \"{}\"", path))))
+            }
+            ".export" => match code[word.len()..].trim() {
+                name if is_identifier(name) => {
+                    self.exports.push((number, String::from(name)));
+                    Ok((vec![], Some(format!(".export {}", name))))
+                }
+                _ => Err(format!("syntax error: expected .export <name>: {}", code)),
+            },
+            w if w.starts_with('.') => {
+                let code = parse_directive(code, &mut self.symbols)?;
+                Ok((vec![], Some(code)))
+            }
+            _ if self.macros.contains_key(word) => {
+                let args = code[word.len()..].trim();
+                let instructions = self.macros[word].expand(word, args, self.address)?;
+                Ok((instructions, Some(String::from(code))))
+            }
+            _ => {
+                let instruction = Instruction::parse(code, &self.symbols)?;
+                // An instruction that doesn't parse without the symbols uses some of them
+                let code = match Instruction::parse(code, &Symbols::default()) {
+                    Ok(_) => format!("{:?}", instruction),
+                    Err(_) => written(code),
+                };
+                Ok((vec![instruction], Some(code)))
+            }
+        }
+    }
+
+    // Name and parameters of a macro definition
+    fn define(&self, code: &str) -> result::Result<(String, Vec<String>), String> {
+        let (name, params) = match code[".macro".len()..].trim_start().split_once(' ') {
+            Some((name, params)) => (name, params.trim()),
+            None => (code[".macro".len()..].trim_start(), ""),
+        };
+        if name.is_empty() {
+            return Err(format!("syntax error: expected .macro <name> <parameters>: {}", code));
+        }
+        if !is_identifier(name) || Instruction::is_opcode(name) {
+            return Err(format!("syntax error: invalid macro name: {}", name));
+        }
+        if self.macros.contains_key(name) {
+            return Err(format!("macro already defined: {}", name));
+        }
+        let mut names: Vec<String> = Vec::new();
+        for param in params.split([',', ' ']).filter(|p| !p.is_empty()) {
+            if !is_identifier(param) || names.iter().any(|p| p == param) {
+                return Err(format!("syntax error: invalid macro parameter: {}", param));
+            }
+            names.push(String::from(param));
+        }
+        Ok((String::from(name), names))
+    }
+
+    // Block declared by a line of the `.data` section, `<label>: .<type> <values>`, the values
+    // being payloads of the given type. Returns its canonical text.
+    fn declare(&mut self, code: &str) -> result::Result<String, String> {
+        let (label, declaration) = code.split_once(':').unwrap();
+        let (directive, values) = match declaration.trim().split_once(' ') {
+            Some((directive, values)) => (directive, split_list(values.trim())),
+            None => (declaration.trim(), Vec::new()),
+        };
+        if !is_identifier(label) {
+            return Err(format!("syntax error: invalid data label: {}", label));
+        }
+        if self.symbols.declares(label) {
+            return Err(format!("name already declared: {}", label));
+        }
+        // Arrays and tuples are not declarable, a block being already a sequence of values
+        let t = match directive.strip_prefix('.').map(Type::parse) {
+            Some(Ok(t)) if t != Type::Array && t != Type::Tuple => t,
+            _ => return Err(format!("syntax error: unknown data type: {}", directive)),
+        };
+        if values.is_empty() {
+            return Err(format!("syntax error: no values for data label: {}", label));
+        }
+        let mut block = Vec::new();
+        for v in &values {
+            block.push(parse_value(&format!("{}({})", t, v), &self.symbols)?);
+        }
+        self.symbols.data.insert(String::from(label), self.data.len());
+        self.data.push(block);
+        Ok(format!("{}: .{} {}", label, t, values.join(", ")))
+    }
+
+    // Code label defined by a line `<label>:`, as the address of the next instruction. Returns its
+    // canonical text.
+    fn label(&mut self, word: &str) -> result::Result<String, String> {
+        let label = &word[..word.len() - 1];
+        if !is_identifier(label) || Instruction::is_opcode(label) {
+            return Err(format!("syntax error: invalid label: {}", label));
+        }
+        if self.symbols.declares(label) {
+            return Err(format!("name already declared: {}", label));
+        }
+        self.symbols.labels.insert(String::from(label), self.address);
+        Ok(String::from(word))
+    }
+
+    // Instructions of an included file, whose exported macros and register aliases are declared in
+    // this one
+    fn include(&mut self, path: &str) -> result::Result<Vec<Instruction>, String> {
+        let file = resolve_include(path, self.file, self.search)?;
+        let canonical = match file.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(why) => return Err(format!("couldn't open {}: {}", path, why)),
+        };
+        if self.including.contains(&canonical) {
+            return Err(format!("include cycle: {} is already being included", path));
+        }
+        let text = match fs::read_to_string(&file) {
+            Ok(text) => text,
+            Err(why) => return Err(format!("couldn't open {}: {}", path, why)),
+        };
+        let mut including = self.including.clone();
+        including.push(canonical);
+        let mut assembler = Assembler::new(Some(&file), self.search, including);
+        assembler.included = Some(String::from(path));
+        // Data blocks are numbered across files, and so are instructions
+        assembler.data = std::mem::take(&mut self.data);
+        assembler.address = self.address;
+        let instructions = match parse(&text, &mut assembler) {
+            Ok(source) => source.into_iter().flat_map(|l| l.instructions).collect(),
+            Err(e) => return Err(format!("in {}: {}", path, e)),
+        };
+        self.data = std::mem::take(&mut assembler.data);
+        let exports = std::mem::take(&mut assembler.exports);
+        for (_, name) in exports {
+            if let Some(m) = assembler.macros.remove(&name) {
+                if self.macros.insert(name.clone(), m).is_some() {
+                    return Err(format!("macro already defined: {} (exported by {})", name, path));
+                }
+                continue;
+            }
+            if self.symbols.declares(&name) {
+                return Err(format!("name already declared: {} (exported by {})", name, path));
+            }
+            if let Some(r) = assembler.symbols.registers.remove(&name) {
+                self.symbols.registers.insert(name, r);
+            } else if let Some(v) = assembler.symbols.constants.remove(&name) {
+                self.symbols.constants.insert(name, v);
+            } else if let Some(n) = assembler.symbols.data.remove(&name) {
+                self.symbols.data.insert(name, n);
+            } else if let Some(addr) = assembler.symbols.labels.remove(&name) {
+                self.symbols.labels.insert(name, addr);
+            }
+        }
+        Ok(instructions)
+    }
+}
+
+// Code as written, with its opcode lowercase, for the lines that can't be written canonically
+fn written(code: &str) -> String {
+    match code.split_once(' ') {
+        Some((opcode, operands)) if Instruction::is_opcode(opcode) => {
+            format!("{} {}", opcode.to_ascii_lowercase(), operands.trim())
+        }
+        None if Instruction::is_opcode(code) => code.to_ascii_lowercase(),
+        _ => String::from(code),
+    }
+}
+
+// Source lines of the text of a file, declaring the symbols and macros of the given assembler.
+// Labels may be used before they are defined: a first pass collects their addresses, taking the
+// unknown ones as address 0.
+fn parse(text: &str, assembler: &mut Assembler) -> result::Result<Vec<SourceLine>, String> {
+    let mut first = assembler.fork();
+    first.symbols.any_label = true;
+    parse_lines(text, &mut first)?;
+    assembler.symbols.later = first.symbols.labels;
+    parse_lines(text, assembler)
+}
+
+fn parse_lines(text: &str, assembler: &mut Assembler) -> result::Result<Vec<SourceLine>, String> {
+    let mut source = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let (code, comment) = match comment_start(line) {
+            Some(a) => (&line[..a], Some(String::from(line[a + 1..].trim_end()))),
+            None => (line, None),
+        };
+        let code = code.trim().replace('\t', " ");
+        let (instructions, code) = match assembler.line(number + 1, &code) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("line {}: {}", number + 1, e)),
+        };
+        assembler.address += instructions.len();
+        source.push(SourceLine {
+            instructions,
+            code,
+            comment,
+        });
+    }
+    if let Some((name, _)) = &assembler.defining {
+        return Err(format!("unterminated macro: {} (missing .endm)", name));
+    }
+    // Exported names must be declared by the end of the file
+    for (number, name) in &assembler.exports {
+        if !assembler.macros.contains_key(name) && !assembler.symbols.declares(name) {
+            return Err(format!("line {}: exported name is not declared: {}", number, name));
+        }
+    }
+    Ok(source)
+}
+
+// Source lines of a text, whose included files are searched from the current directory
+#[cfg(test)]
+pub fn parse_source(text: &str) -> result::Result<Vec<SourceLine>, String> {
+    parse(text, &mut Assembler::new(None, &[], Vec::new()))
+}
+
+// Source lines and data blocks of a file, whose included files are searched from its directory
+// and then from the given ones
+fn assemble(filename: &str, search: &[PathBuf]) -> result::Result<(Vec<SourceLine>, Data), String> {
+    let text = match fs::read_to_string(filename) {
+        Err(why) => return Err(format!("couldn't open {}: {}", filename, why)),
+        Ok(text) => text,
+    };
+    let including = match Path::new(filename).canonicalize() {
+        Ok(canonical) => vec![canonical],
+        Err(_) => Vec::new(),
+    };
+    let mut assembler = Assembler::new(Some(Path::new(filename)), search, including);
+    let source = parse(&text, &mut assembler)?;
+    Ok((source, assembler.data))
+}
+
+pub fn read_source(filename: &str, search: &[PathBuf]) -> result::Result<Vec<SourceLine>, String> {
+    assemble(filename, search).map(|(source, _)| source)
+}
+
+pub fn compile_asm(
+    filename: &str,
+    search: &[PathBuf],
+) -> result::Result<(Program, LineTable, Data), String> {
+    match assemble(filename, search) {
+        Err(e) => Err(e),
+        Ok((source, data)) => {
+            let mut prog = Program::new();
+            let mut lines = LineTable::new();
+            // The instructions expanded from a macro or an included file are located at the line
+            // of its call or `.include`
+            for (number, line) in source.into_iter().enumerate() {
+                for instruction in line.instructions {
+                    prog.push(instruction);
+                    lines.push(number + 1);
+                }
             }
-            Ok(prog)
+            Ok((prog, lines, data))
         }
     }
 }
@@ -0,0 +1,200 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::result;
+
+use crate::program::comment_start;
+
+// Expected results of a test program. They are given either inline, by `; expect: <line>`,
+// `; expect-err: <line>` and `; expect-code: <n>` comments in the program, or by the sidecar files
+// `<program>.out`, `<program>.err` and `<program>.code`. Missing ones default to no output and a
+// 0 exit code. Inline lines keep their whitespace, but for the space after the colon. VM options to
+// run the program with can be given by a `; args: <options>` comment.
+struct Expectations {
+    args: Vec<String>,
+    stdout: String,
+    stderr: String,
+    code: i32,
+    inline: bool,
+}
+
+struct Outcome {
+    stdout: String,
+    stderr: String,
+    code: i32,
+}
+
+fn sidecar(program: &Path, extension: &str) -> PathBuf {
+    let mut name = program.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+fn read_expectations(program: &Path) -> result::Result<Expectations, String> {
+    let text = match fs::read_to_string(program) {
+        Ok(text) => text,
+        Err(why) => return Err(format!("couldn't open {}: {}", program.display(), why)),
+    };
+    let (mut stdout, mut stderr, mut code) = (None::<String>, None::<String>, None);
+    let mut args = Vec::new();
+    for line in text.lines() {
+        let comment = match comment_start(line) {
+            Some(a) => line[a + 1..].trim_start(),
+            None => continue,
+        };
+        // Only the space separating an expected line from its colon is dropped, the line being
+        // kept as is otherwise
+        let expected = |v: &str| format!("{}\n", v.strip_prefix(' ').unwrap_or(v));
+        if let Some(v) = comment.strip_prefix("args:") {
+            args.extend(v.split_whitespace().map(String::from));
+        } else if let Some(v) = comment.strip_prefix("expect:") {
+            stdout.get_or_insert_with(String::new).push_str(&expected(v));
+        } else if let Some(v) = comment.strip_prefix("expect-err:") {
+            stderr.get_or_insert_with(String::new).push_str(&expected(v));
+        } else if let Some(v) = comment.strip_prefix("expect-code:") {
+            match v.trim().parse::<i32>() {
+                Ok(v) => code = Some(v),
+                Err(_) => return Err(format!("invalid expected exit code: {}", v.trim())),
+            }
+        }
+    }
+    let inline = stdout.is_some() || stderr.is_some() || code.is_some();
+    let read = |extension| fs::read_to_string(sidecar(program, extension)).ok();
+    let code = match code {
+        Some(code) => code,
+        None => match read("code") {
+            None => 0,
+            Some(v) => match v.trim().parse::<i32>() {
+                Ok(v) => v,
+                Err(_) => return Err(format!("invalid expected exit code: {}", v.trim())),
+            },
+        },
+    };
+    Ok(Expectations {
+        args,
+        stdout: stdout.or_else(|| read("out")).unwrap_or_default(),
+        stderr: stderr.or_else(|| read("err")).unwrap_or_default(),
+        code,
+        inline,
+    })
+}
+
+// Programs are run by a separate VM process, so that their output can be captured
+fn run_program(program: &Path, args: &[String]) -> result::Result<Outcome, String> {
+    let vm = match env::current_exe() {
+        Ok(vm) => vm,
+        Err(why) => return Err(format!("couldn't find the VM executable: {}", why)),
+    };
+    match Command::new(vm)
+        .args(args)
+        .arg(program)
+        .env_remove("RUST_BACKTRACE")
+        .output()
+    {
+        Err(why) => Err(format!("couldn't run {}: {}", program.display(), why)),
+        Ok(output) => Ok(Outcome {
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            code: output.status.code().unwrap_or(-1),
+        }),
+    }
+}
+
+fn write_sidecar(program: &Path, extension: &str, content: &str) -> result::Result<(), String> {
+    let path = sidecar(program, extension);
+    let written = match content.is_empty() && extension != "out" {
+        // Default expectations are not written, and stale ones are removed
+        true => match path.exists() {
+            true => fs::remove_file(&path),
+            false => Ok(()),
+        },
+        false => fs::write(&path, content),
+    };
+    match written {
+        Ok(_) => Ok(()),
+        Err(why) => Err(format!("couldn't write {}: {}", path.display(), why)),
+    }
+}
+
+fn bless(program: &Path, outcome: &Outcome) -> result::Result<(), String> {
+    let code = match outcome.code {
+        0 => String::new(),
+        code => format!("{}\n", code),
+    };
+    write_sidecar(program, "out", &outcome.stdout)?;
+    write_sidecar(program, "err", &outcome.stderr)?;
+    write_sidecar(program, "code", &code)
+}
+
+fn report_mismatch(what: &str, expected: &str, actual: &str) {
+    println!("  {} mismatch", what);
+    println!("  --- expected");
+    for line in expected.lines() {
+        println!("  | {}", line);
+    }
+    println!("  --- actual");
+    for line in actual.lines() {
+        println!("  | {}", line);
+    }
+}
+
+// Runs every `.avm` program of a directory and compares its results with the expected ones, or
+// with `bless` overwrites the sidecar files with the actual results. Returns whether all passed.
+pub fn run_tests(dir: &str, blessing: bool) -> result::Result<bool, String> {
+    let mut programs: Vec<PathBuf> = match fs::read_dir(dir) {
+        Err(why) => return Err(format!("couldn't open {}: {}", dir, why)),
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "avm"))
+            .collect(),
+    };
+    programs.sort();
+
+    let (mut passed, mut failed, mut blessed) = (0, 0, 0);
+    for program in &programs {
+        let expected = read_expectations(program)?;
+        let outcome = run_program(program, &expected.args)?;
+        if blessing && !expected.inline {
+            bless(program, &outcome)?;
+            println!("test {} ... blessed", program.display());
+            blessed += 1;
+            continue;
+        }
+        let ok = outcome.stdout == expected.stdout
+            && outcome.stderr == expected.stderr
+            && outcome.code == expected.code;
+        println!(
+            "test {} ... {}",
+            program.display(),
+            if ok { "ok" } else { "FAILED" }
+        );
+        if ok {
+            passed += 1;
+            continue;
+        }
+        failed += 1;
+        if outcome.stdout != expected.stdout {
+            report_mismatch("stdout", &expected.stdout, &outcome.stdout);
+        }
+        if outcome.stderr != expected.stderr {
+            report_mismatch("stderr", &expected.stderr, &outcome.stderr);
+        }
+        if outcome.code != expected.code {
+            println!(
+                "  exit code mismatch: expected {}, actual {}",
+                expected.code, outcome.code
+            );
+        }
+    }
+    println!();
+    println!(
+        "test result: {}. {} passed; {} failed; {} blessed",
+        if failed == 0 { "ok" } else { "FAILED" },
+        passed,
+        failed,
+        blessed
+    );
+    Ok(failed == 0)
+}
@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::fmt::{Debug, Display, Formatter, Result};
 use std::ops::{Add, Div, Mul, Rem, Sub};
 use std::result;
@@ -16,6 +17,69 @@ pub enum Value {
     Float(f32),
     Double(f64),
     BigDecimal(BigDecimal),
+    String(String),
+    Array(Vec<Value>), // elements all of the same type (see `Value::array`)
+    Tuple(Vec<Value>),
+    Bool(bool),
+    Char(char),
+}
+
+// Type of a value, ordered by rank: arithmetic on values of different types gives a value of the
+// highest ranked one (see `apply_operator!`)
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Type {
+    Int8,
+    Int16,
+    Int32,
+    Float,
+    Double,
+    BigDecimal,
+    String, // not a numeric type, no arithmetic applies to it
+    Array,  // arithmetic applies element-wise (see `element_wise`)
+    Tuple,  // no arithmetic applies to it
+    Bool,   // nor to booleans and characters, converted to integers by `cast` (see `Value::cast`)
+    Char,
+}
+
+impl Type {
+    pub fn parse(s: &str) -> result::Result<Type, String> {
+        match s {
+            "int8" => Ok(Type::Int8),
+            "int16" => Ok(Type::Int16),
+            "int32" => Ok(Type::Int32),
+            "float" => Ok(Type::Float),
+            "double" => Ok(Type::Double),
+            "bigdecimal" => Ok(Type::BigDecimal),
+            "string" => Ok(Type::String),
+            "array" => Ok(Type::Array),
+            "tuple" => Ok(Type::Tuple),
+            "bool" => Ok(Type::Bool),
+            "char" => Ok(Type::Char),
+            _ => Err(format!("syntax error: unknown value type: {}", s)),
+        }
+    }
+
+    pub fn promote(self, other: Type) -> Type {
+        self.max(other)
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Type::Int8 => write!(f, "int8"),
+            Type::Int16 => write!(f, "int16"),
+            Type::Int32 => write!(f, "int32"),
+            Type::Float => write!(f, "float"),
+            Type::Double => write!(f, "double"),
+            Type::BigDecimal => write!(f, "bigdecimal"),
+            Type::String => write!(f, "string"),
+            Type::Array => write!(f, "array"),
+            Type::Tuple => write!(f, "tuple"),
+            Type::Bool => write!(f, "bool"),
+            Type::Char => write!(f, "char"),
+        }
+    }
 }
 
 impl Display for Value {
@@ -27,6 +91,11 @@ impl Display for Value {
             Value::Float(arg) => write!(f, "{}", arg),
             Value::Double(arg) => write!(f, "{}", arg),
             Value::BigDecimal(arg) => write!(f, "{}", arg),
+            Value::String(arg) => write!(f, "{}", arg),
+            Value::Array(arg) => write!(f, "[{}]", join(arg, |v| format!("{}", v))),
+            Value::Tuple(arg) => write!(f, "({})", join(arg, |v| format!("{}", v))),
+            Value::Bool(arg) => write!(f, "{}", arg),
+            Value::Char(arg) => write!(f, "{}", arg),
         }
     }
 }
@@ -40,14 +109,237 @@ impl Debug for Value {
             Value::Float(arg) => write!(f, "float({})", arg),
             Value::Double(arg) => write!(f, "double({})", arg),
             Value::BigDecimal(arg) => write!(f, "bigdecimal({})", arg),
+            Value::String(arg) => write!(f, "string(\"{}\")", escape(arg)),
+            Value::Array(arg) => write!(f, "array({})", join(arg, |v| format!("{:?}", v))),
+            Value::Tuple(arg) => write!(f, "tuple({})", join(arg, |v| format!("{:?}", v))),
+            Value::Bool(arg) => write!(f, "bool({})", arg),
+            Value::Char('\'') => write!(f, "char('\\'')"),
+            Value::Char('"') => write!(f, "char('\"')"),
+            Value::Char(arg) => write!(f, "char('{}')", escape(&arg.to_string())),
         }
     }
 }
 
+// Greatest digit count of a bigdecimal literal, the value being written in plain notation
+pub const MAX_LITERAL_DIGITS: u64 = 1 << 16;
+
+// Digit count of a bigdecimal written in plain notation, so that the exponent counts too
+pub fn plain_digits(v: &BigDecimal) -> u64 {
+    let (_, scale) = v.as_bigint_and_exponent();
+    let digits = v.digits() as i128;
+    digits.max(digits - scale as i128).max(scale as i128) as u64
+}
+
+fn join(values: &[Value], f: impl Fn(&Value) -> String) -> String {
+    values.iter().map(f).collect::<Vec<String>>().join(", ")
+}
+
 impl Value {
+    // Array of the given elements, which must all be of the same type
+    pub fn array(elements: Vec<Value>) -> result::Result<Value, String> {
+        if let Some(first) = elements.first() {
+            if let Some(v) = elements.iter().find(|v| v.get_type() != first.get_type()) {
+                return Err(format!(
+                    "array elements of different types: {} and {}",
+                    first.get_type(),
+                    v.get_type()
+                ));
+            }
+        }
+        Ok(Value::Array(elements))
+    }
+
+    pub fn get_type(&self) -> Type {
+        match self {
+            Value::Int8(_) => Type::Int8,
+            Value::Int16(_) => Type::Int16,
+            Value::Int32(_) => Type::Int32,
+            Value::Float(_) => Type::Float,
+            Value::Double(_) => Type::Double,
+            Value::BigDecimal(_) => Type::BigDecimal,
+            Value::String(_) => Type::String,
+            Value::Array(_) => Type::Array,
+            Value::Tuple(_) => Type::Tuple,
+            Value::Bool(_) => Type::Bool,
+            Value::Char(_) => Type::Char,
+        }
+    }
+
+    // Conversion to another type by the `cast` instruction: between integer types (checking the
+    // value fits), from an integer to a higher ranked numeric type (as promoted by arithmetic), and
+    // between integers and booleans (0 being false) or characters (as Unicode code points)
+    pub fn cast(&self, t: Type) -> result::Result<Value, String> {
+        let integer = match self {
+            Value::Bool(v) => Some(*v as i64),
+            Value::Char(v) => Some(*v as i64),
+            v => v.as_integer(),
+        };
+        let cast = match (integer, t) {
+            (_, t) if self.get_type() == t => Some(self.clone()),
+            (Some(v), Type::Int8) => i8::try_from(v).ok().map(Value::Int8),
+            (Some(v), Type::Int16) => i16::try_from(v).ok().map(Value::Int16),
+            (Some(v), Type::Int32) => i32::try_from(v).ok().map(Value::Int32),
+            (Some(v), Type::Bool) if self.as_integer().is_some() => Some(Value::Bool(v != 0)),
+            (Some(v), Type::Char) if self.as_integer().is_some() => u32::try_from(v)
+                .ok()
+                .and_then(char::from_u32)
+                .map(Value::Char),
+            (Some(_), t) if self.as_integer().is_some() => self.promote_to(t),
+            _ => None,
+        };
+        match cast {
+            Some(v) => Ok(v),
+            None => Err(format!("cannot convert {:?} to {}", self, t)),
+        }
+    }
+
+    // Conversion to a higher ranked type, the way `apply_operator!` does it (`None` if this is not
+    // a promotion, or if the value can't be represented, as NaN for a bigdecimal)
+    pub fn promote_to(&self, t: Type) -> Option<Value> {
+        if self.get_type() == t {
+            return Some(self.clone());
+        }
+        match (self, t) {
+            (Value::Int8(v), Type::Int16) => Some(Value::Int16(*v as i16)),
+            (Value::Int8(v), Type::Int32) => Some(Value::Int32(*v as i32)),
+            (Value::Int16(v), Type::Int32) => Some(Value::Int32(*v as i32)),
+            (Value::Int8(v), Type::Float) => Some(Value::Float(*v as f32)),
+            (Value::Int16(v), Type::Float) => Some(Value::Float(*v as f32)),
+            (Value::Int32(v), Type::Float) => Some(Value::Float(*v as f32)),
+            (Value::Int8(v), Type::Double) => Some(Value::Double(*v as f64)),
+            (Value::Int16(v), Type::Double) => Some(Value::Double(*v as f64)),
+            (Value::Int32(v), Type::Double) => Some(Value::Double(*v as f64)),
+            (Value::Float(v), Type::Double) => Some(Value::Double(*v as f64)),
+            (Value::Int8(v), Type::BigDecimal) => BigDecimal::from_i8(*v).map(Value::BigDecimal),
+            (Value::Int16(v), Type::BigDecimal) => BigDecimal::from_i16(*v).map(Value::BigDecimal),
+            (Value::Int32(v), Type::BigDecimal) => BigDecimal::from_i32(*v).map(Value::BigDecimal),
+            (Value::Float(v), Type::BigDecimal) => BigDecimal::from_f32(*v).map(Value::BigDecimal),
+            (Value::Double(v), Type::BigDecimal) => BigDecimal::from_f64(*v).map(Value::BigDecimal),
+            _ => None,
+        }
+    }
+
+    // Ordering of two values of any types, compared once promoted to their common type (`None` if
+    // they are not comparable, as NaN). Arrays and tuples are compared element by element.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        if let (Value::Array(a), Value::Array(b)) | (Value::Tuple(a), Value::Tuple(b)) = (self, other) {
+            for (a, b) in a.iter().zip(b) {
+                match a.compare(b)? {
+                    Ordering::Equal => {}
+                    ordering => return Some(ordering),
+                }
+            }
+            return Some(a.len().cmp(&b.len()));
+        }
+        let t = self.get_type().promote(other.get_type());
+        match (self.promote_to(t)?, other.promote_to(t)?) {
+            (Value::Int8(a), Value::Int8(b)) => a.partial_cmp(&b),
+            (Value::Int16(a), Value::Int16(b)) => a.partial_cmp(&b),
+            (Value::Int32(a), Value::Int32(b)) => a.partial_cmp(&b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(&b),
+            (Value::Double(a), Value::Double(b)) => a.partial_cmp(&b),
+            (Value::BigDecimal(a), Value::BigDecimal(b)) => a.partial_cmp(&b),
+            (Value::String(a), Value::String(b)) => a.partial_cmp(&b),
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(&b),
+            (Value::Char(a), Value::Char(b)) => a.partial_cmp(&b),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Int8(v) => Some(*v as i64),
+            Value::Int16(v) => Some(*v as i64),
+            Value::Int32(v) => Some(*v as i64),
+            _ => None,
+        }
+    }
+
+    // Greatest digit count of the bigdecimals in a value, elements included, as written in plain
+    // notation
+    pub fn digits(&self) -> u64 {
+        match self {
+            Value::BigDecimal(v) => plain_digits(v),
+            Value::Array(elements) | Value::Tuple(elements) => {
+                elements.iter().map(Value::digits).max().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn is_nan(&self) -> bool {
+        match self {
+            Value::Float(v) => v.is_nan(),
+            Value::Double(v) => v.is_nan(),
+            _ => false,
+        }
+    }
+
+    // Equality of values of any types, once promoted to their common type, NaN being equal to
+    // itself. This is the equality of assertions in loose mode, the strict one being `==`.
+    pub fn loose_eq(&self, other: &Value) -> bool {
+        (self.is_nan() && other.is_nan()) || self.compare(other) == Some(Ordering::Equal)
+    }
+
+    // Whether two values of any types differ by at most epsilon. Values are compared as doubles at
+    // least, so that integers and floats are represented exactly, or as bigdecimals.
+    pub fn approx_eq(&self, other: &Value, epsilon: &Value) -> bool {
+        if self.is_nan() && other.is_nan() {
+            return true;
+        }
+        let t = Type::Double
+            .promote(self.get_type())
+            .promote(other.get_type())
+            .promote(epsilon.get_type());
+        match (
+            self.promote_to(t),
+            other.promote_to(t),
+            epsilon.promote_to(t),
+        ) {
+            (Some(Value::Double(a)), Some(Value::Double(b)), Some(Value::Double(e))) => {
+                a == b || (a - b).abs() <= e // infinities are only equal to themselves
+            }
+            (Some(Value::BigDecimal(a)), Some(Value::BigDecimal(b)), Some(Value::BigDecimal(e))) => {
+                (a - b).abs() <= e
+            }
+            _ => false,
+        }
+    }
+
+    // Whether two values differ by at most the given number of units in the last place: as floats
+    // if both are, as doubles otherwise. `None` for bigdecimals, which have no such unit.
+    pub fn ulps_eq(&self, other: &Value, ulps: u32) -> Option<bool> {
+        if self.is_nan() || other.is_nan() {
+            return Some(self.is_nan() && other.is_nan());
+        }
+        // Floating point bits (as a signed integer of the same width, whose minimum is given),
+        // mapped to integers having the same ordering as the values
+        fn ordered(bits: i64, min: i64) -> i128 {
+            match bits < 0 {
+                true => min as i128 - bits as i128,
+                false => bits as i128,
+            }
+        }
+        let distance = match (self, other) {
+            (Value::BigDecimal(_), _) | (_, Value::BigDecimal(_)) => return None,
+            (Value::Float(a), Value::Float(b)) => {
+                let min = i32::MIN as i64;
+                ordered(a.to_bits() as i32 as i64, min) - ordered(b.to_bits() as i32 as i64, min)
+            }
+            (a, b) => match (a.promote_to(Type::Double), b.promote_to(Type::Double)) {
+                (Some(Value::Double(a)), Some(Value::Double(b))) => {
+                    ordered(a.to_bits() as i64, i64::MIN) - ordered(b.to_bits() as i64, i64::MIN)
+                }
+                _ => return None,
+            },
+        };
+        Some(distance.unsigned_abs() <= ulps as u128)
+    }
+
     pub fn parse(s: &str) -> result::Result<Value, String> {
-        match (s.find('('), s.find(')')) {
-            (Some(a), Some(b)) => {
+        // The closing parenthesis is the last one, as string literals may hold some
+        match (s.find('('), s.rfind(')')) {
+            (Some(a), Some(b)) if a < b => {
                 let first_token = s[..a].trim();
                 let second_token = s[a + 1..b].trim();
                 match first_token {
@@ -72,9 +364,56 @@ impl Value {
                         Err(_) => Err(format!("syntax error: illegal double: {}", s)),
                     },
                     "bigdecimal" => match second_token.parse::<BigDecimal>() {
+                        Ok(v) if plain_digits(&v) > MAX_LITERAL_DIGITS => {
+                            Err(format!("syntax error: bigdecimal out of range: {}", s))
+                        }
                         Ok(v) => Ok(Value::BigDecimal(v)),
                         Err(_) => Err(format!("syntax error: illegal bigdecimal: {}", s)),
                     },
+                    "string" => match second_token
+                        .strip_prefix('"')
+                        .and_then(|t| t.strip_suffix('"'))
+                    {
+                        Some(v) => match unescape(v) {
+                            Ok(v) => Ok(Value::String(v)),
+                            Err(e) => Err(format!("syntax error: {}: {}", e, s)),
+                        },
+                        None => Err(format!("syntax error: missing quotes: {}", s)),
+                    },
+                    "bool" => match second_token {
+                        "true" => Ok(Value::Bool(true)),
+                        "false" => Ok(Value::Bool(false)),
+                        _ => Err(format!("syntax error: illegal bool: {}", s)),
+                    },
+                    "char" => match second_token
+                        .strip_prefix('\'')
+                        .and_then(|t| t.strip_suffix('\''))
+                    {
+                        Some("\"") => Ok(Value::Char('"')),
+                        Some("\\'") => Ok(Value::Char('\'')),
+                        Some("'") => Err(format!("syntax error: unescaped quote in char: {}", s)),
+                        Some(v) => match unescape(v) {
+                            Ok(v) => match (v.chars().next(), v.chars().count()) {
+                                (Some(c), 1) => Ok(Value::Char(c)),
+                                _ => Err(format!("syntax error: not a single character: {}", s)),
+                            },
+                            Err(e) => Err(format!("syntax error: {}: {}", e, s)),
+                        },
+                        None => Err(format!("syntax error: missing quotes: {}", s)),
+                    },
+                    "array" | "tuple" => {
+                        let mut elements = Vec::new();
+                        for element in split_list(second_token) {
+                            elements.push(Value::parse(element)?);
+                        }
+                        match first_token {
+                            "tuple" => Ok(Value::Tuple(elements)),
+                            _ => match Value::array(elements) {
+                                Ok(v) => Ok(v),
+                                Err(e) => Err(format!("syntax error: {}: {}", e, s)),
+                            },
+                        }
+                    }
                     _ => Err(format!("syntax error: unknown value type: {}", s)),
                 }
             }
@@ -83,41 +422,208 @@ impl Value {
     }
 }
 
+// Items of a comma-separated list, as the elements of an array or tuple literal, the commas being
+// neither in a nested value nor in a string or char literal
+pub fn split_list(s: &str) -> Vec<&str> {
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    let mut chars = s.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', Some(_)) => {
+                chars.next(); // escaped character, possibly a quote
+            }
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('(', None) => depth += 1,
+            (')', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                elements.push(s[start..offset].trim());
+                start = offset + 1;
+            }
+            _ => {}
+        }
+    }
+    if !s[start..].trim().is_empty() || !elements.is_empty() {
+        elements.push(s[start..].trim());
+    }
+    elements
+}
+
+// Escape sequences of string literals
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(s: &str) -> result::Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Err(String::from("unescaped quote in string")),
+            '\\' => match chars.next() {
+                Some('"') => unescaped.push('"'),
+                Some('\\') => unescaped.push('\\'),
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some('r') => unescaped.push('\r'),
+                Some('0') => unescaped.push('\0'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    match code
+                        .strip_prefix('{')
+                        .and_then(|c| u32::from_str_radix(c, 16).ok())
+                        .and_then(char::from_u32)
+                    {
+                        Some(c) => unescaped.push(c),
+                        None => return Err(format!("invalid unicode escape \\u{}}}", code)),
+                    }
+                }
+                Some(c) => return Err(format!("unknown escape sequence \\{}", c)),
+                None => return Err(String::from("unterminated escape sequence")),
+            },
+            c => unescaped.push(c),
+        }
+    }
+    Ok(unescaped)
+}
+
 /////////////////////////////
 // Operators implementations
 /////////////////////////////
 
-// Note: all arithmetic errors (div/mod by zero, overflow, underflow) are already handled by Rust
-// through panics. We just have to catch them before doing the operation if needed (for example, to
-// return 84 in case of such panic)
+// Error of an arithmetic operation, raised by the VM with the error kind it maps to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArithmeticError {
+    DivisionByZero,
+    Overflow,
+    InvalidOperand(String),
+}
+
+impl Display for ArithmeticError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            ArithmeticError::DivisionByZero => write!(f, "division by zero"),
+            ArithmeticError::Overflow => write!(f, "overflow"),
+            ArithmeticError::InvalidOperand(m) => write!(f, "invalid operand: {}", m),
+        }
+    }
+}
+
+// Result of an arithmetic operation
+pub type Arithmetic = result::Result<Value, ArithmeticError>;
+
+// Strings, tuples, booleans and characters have no arithmetic
+fn invalid_operand(t: Type) -> ArithmeticError {
+    ArithmeticError::InvalidOperand(format!("arithmetic on a {}", t))
+}
+
+// Bigdecimal value of a float operand, which NaN and infinities have not
+fn decimal(v: &Value) -> result::Result<BigDecimal, ArithmeticError> {
+    match v.promote_to(Type::BigDecimal) {
+        Some(Value::BigDecimal(v)) => Ok(v),
+        _ => Err(ArithmeticError::InvalidOperand(format!("{:?} has no bigdecimal value", v))),
+    }
+}
+
+// Result of a checked integer operation
+fn no_overflow<T>(v: Option<T>) -> result::Result<T, ArithmeticError> {
+    v.ok_or(ArithmeticError::Overflow)
+}
+
+// Checks the divisor of a division or modulo: floating point values have a result for a zero
+// divisor (an infinity or NaN), and non-numeric operands are invalid whatever the divisor
+fn divisor(a: &Value, b: &Value) -> result::Result<(), ArithmeticError> {
+    match a.get_type().promote(b.get_type()) {
+        Type::Int8 | Type::Int16 | Type::Int32 | Type::BigDecimal
+            if b.compare(&Value::Int8(0)) == Some(Ordering::Equal) =>
+        {
+            Err(ArithmeticError::DivisionByZero)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Arithmetic with an array operand: applied to each pair of elements of two arrays of the same
+// length, or to each element of an array and a scalar, in the same operand order. Elements are
+// promoted as scalars are: an array of int8 multiplied by a double gives an array of double.
+fn element_wise(a: Value, b: Value, op: fn(Value, Value) -> Arithmetic) -> Arithmetic {
+    let elements: result::Result<Vec<Value>, ArithmeticError> = match (a, b) {
+        (Value::Array(a), Value::Array(b)) => {
+            if a.len() != b.len() {
+                return Err(ArithmeticError::InvalidOperand(format!(
+                    "arrays of different lengths: {} and {}",
+                    a.len(),
+                    b.len()
+                )));
+            }
+            a.into_iter().zip(b).map(|(a, b)| op(a, b)).collect()
+        }
+        (Value::Array(a), b) => a.into_iter().map(|a| op(a, b.clone())).collect(),
+        (a, Value::Array(b)) => b.into_iter().map(|b| op(a.clone(), b)).collect(),
+        (a, b) => return op(a, b),
+    };
+    elements.map(Value::Array)
+}
 
-// The dreaded "double-match-NxN" apply-operator, to match every possible combination of types
+// The dreaded "double-match-NxN" apply-operator, to match every possible combination of types.
+// Integer operations are checked, an overflow being returned as an error.
 macro_rules! apply_operator {
-    ($a:ident, $b:ident, $c:tt) => {
+    ($a:ident, $b:ident, $c:tt, $checked:ident) => {
         match $a {
             Value::Int8(arg1) => match $b {
-                Value::Int8(arg2) => Value::Int8(arg1 $c arg2),
-                Value::Int16(arg2) => Value::Int16(arg1 as i16 $c arg2),
-                Value::Int32(arg2) => Value::Int32(arg1 as i32 $c arg2),
+                Value::Int8(arg2) => Value::Int8(no_overflow(arg1.$checked(arg2))?),
+                Value::Int16(arg2) => Value::Int16(no_overflow((arg1 as i16).$checked(arg2))?),
+                Value::Int32(arg2) => Value::Int32(no_overflow((arg1 as i32).$checked(arg2))?),
                 Value::Float(arg2) => Value::Float(arg1 as f32 $c arg2),
                 Value::Double(arg2) => Value::Double(arg1 as f64 $c arg2),
                 Value::BigDecimal(arg2) => Value::BigDecimal(BigDecimal::from_i8(arg1).unwrap() $c arg2),
+                Value::String(_) => return Err(invalid_operand(Type::String)),
+                Value::Array(_) => return Err(invalid_operand(Type::Array)),
+                Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+                Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+                Value::Char(_) => return Err(invalid_operand(Type::Char)),
             },
             Value::Int16(arg1) => match $b {
-                Value::Int8(arg2) => Value::Int16(arg1 $c arg2 as i16),
-                Value::Int16(arg2) => Value::Int16(arg1 $c arg2),
-                Value::Int32(arg2) => Value::Int32(arg1 as i32 $c arg2),
+                Value::Int8(arg2) => Value::Int16(no_overflow(arg1.$checked(arg2 as i16))?),
+                Value::Int16(arg2) => Value::Int16(no_overflow(arg1.$checked(arg2))?),
+                Value::Int32(arg2) => Value::Int32(no_overflow((arg1 as i32).$checked(arg2))?),
                 Value::Float(arg2) => Value::Float(arg1 as f32 $c arg2),
                 Value::Double(arg2) => Value::Double(arg1 as f64 $c arg2),
                 Value::BigDecimal(arg2) => Value::BigDecimal(BigDecimal::from_i16(arg1).unwrap() $c arg2),
+                Value::String(_) => return Err(invalid_operand(Type::String)),
+                Value::Array(_) => return Err(invalid_operand(Type::Array)),
+                Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+                Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+                Value::Char(_) => return Err(invalid_operand(Type::Char)),
             },
             Value::Int32(arg1) => match $b {
-                Value::Int8(arg2) => Value::Int32(arg1 $c arg2 as i32),
-                Value::Int16(arg2) => Value::Int32(arg1 $c arg2 as i32),
-                Value::Int32(arg2) => Value::Int32(arg1 $c arg2),
+                Value::Int8(arg2) => Value::Int32(no_overflow(arg1.$checked(arg2 as i32))?),
+                Value::Int16(arg2) => Value::Int32(no_overflow(arg1.$checked(arg2 as i32))?),
+                Value::Int32(arg2) => Value::Int32(no_overflow(arg1.$checked(arg2))?),
                 Value::Float(arg2) => Value::Float(arg1 as f32 $c arg2),
                 Value::Double(arg2) => Value::Double(arg1 as f64 $c arg2),
                 Value::BigDecimal(arg2) => Value::BigDecimal(BigDecimal::from_i32(arg1).unwrap() $c arg2),
+                Value::String(_) => return Err(invalid_operand(Type::String)),
+                Value::Array(_) => return Err(invalid_operand(Type::Array)),
+                Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+                Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+                Value::Char(_) => return Err(invalid_operand(Type::Char)),
             },
             Value::Float(arg1) => match $b {
                 Value::Int8(arg2) => Value::Float(arg1 $c arg2 as f32),
@@ -125,7 +631,12 @@ macro_rules! apply_operator {
                 Value::Int32(arg2) => Value::Float(arg1 $c arg2 as f32),
                 Value::Float(arg2) => Value::Float(arg1 $c arg2),
                 Value::Double(arg2) => Value::Double(arg1 as f64 $c arg2),
-                Value::BigDecimal(arg2) => Value::BigDecimal(BigDecimal::from_f32(arg1).unwrap() $c arg2),
+                Value::BigDecimal(arg2) => Value::BigDecimal(decimal(&Value::Float(arg1))? $c arg2),
+                Value::String(_) => return Err(invalid_operand(Type::String)),
+                Value::Array(_) => return Err(invalid_operand(Type::Array)),
+                Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+                Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+                Value::Char(_) => return Err(invalid_operand(Type::Char)),
             },
             Value::Double(arg1) => match $b {
                 Value::Int8(arg2) => Value::Double(arg1 $c arg2 as f64),
@@ -133,56 +644,92 @@ macro_rules! apply_operator {
                 Value::Int32(arg2) => Value::Double(arg1 $c arg2 as f64),
                 Value::Float(arg2) => Value::Double(arg1 $c arg2 as f64),
                 Value::Double(arg2) => Value::Double(arg1 $c arg2),
-                Value::BigDecimal(arg2) => Value::BigDecimal(BigDecimal::from_f64(arg1).unwrap() $c arg2),
+                Value::BigDecimal(arg2) => Value::BigDecimal(decimal(&Value::Double(arg1))? $c arg2),
+                Value::String(_) => return Err(invalid_operand(Type::String)),
+                Value::Array(_) => return Err(invalid_operand(Type::Array)),
+                Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+                Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+                Value::Char(_) => return Err(invalid_operand(Type::Char)),
             },
             Value::BigDecimal(arg1) => match $b {
                 Value::Int8(arg2) => Value::BigDecimal(arg1 $c BigDecimal::from_i8(arg2).unwrap()),
                 Value::Int16(arg2) => Value::BigDecimal(arg1 $c BigDecimal::from_i16(arg2).unwrap()),
                 Value::Int32(arg2) => Value::BigDecimal(arg1 $c BigDecimal::from_i32(arg2).unwrap()),
-                Value::Float(arg2) => Value::BigDecimal(arg1 $c BigDecimal::from_f32(arg2).unwrap()),
-                Value::Double(arg2) => Value::BigDecimal(arg1 $c BigDecimal::from_f64(arg2).unwrap()),
+                Value::Float(arg2) => Value::BigDecimal(arg1 $c decimal(&Value::Float(arg2))?),
+                Value::Double(arg2) => Value::BigDecimal(arg1 $c decimal(&Value::Double(arg2))?),
                 Value::BigDecimal(arg2) => Value::BigDecimal(arg1 $c arg2),
+                Value::String(_) => return Err(invalid_operand(Type::String)),
+                Value::Array(_) => return Err(invalid_operand(Type::Array)),
+                Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+                Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+                Value::Char(_) => return Err(invalid_operand(Type::Char)),
             },
+            Value::String(_) => return Err(invalid_operand(Type::String)),
+            Value::Array(_) => return Err(invalid_operand(Type::Array)),
+            Value::Tuple(_) => return Err(invalid_operand(Type::Tuple)),
+            Value::Bool(_) => return Err(invalid_operand(Type::Bool)),
+            Value::Char(_) => return Err(invalid_operand(Type::Char)),
         }
-    }
+    };
 }
 
 impl Add for Value {
-    type Output = Self;
+    type Output = Arithmetic;
 
-    fn add(self, other: Self) -> Self {
-        apply_operator!(self,other,+)
+    fn add(self, other: Self) -> Arithmetic {
+        match (&self, &other) {
+            (Value::Array(_), _) | (_, Value::Array(_)) => element_wise(self, other, Self::add),
+            _ => Ok(apply_operator!(self, other, +, checked_add)),
+        }
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
+    type Output = Arithmetic;
 
-    fn sub(self, other: Self) -> Self {
-        apply_operator!(self,other,-)
+    fn sub(self, other: Self) -> Arithmetic {
+        match (&self, &other) {
+            (Value::Array(_), _) | (_, Value::Array(_)) => element_wise(self, other, Self::sub),
+            _ => Ok(apply_operator!(self, other, -, checked_sub)),
+        }
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
+    type Output = Arithmetic;
 
-    fn mul(self, other: Self) -> Self {
-        apply_operator!(self,other,*)
+    fn mul(self, other: Self) -> Arithmetic {
+        match (&self, &other) {
+            (Value::Array(_), _) | (_, Value::Array(_)) => element_wise(self, other, Self::mul),
+            _ => Ok(apply_operator!(self, other, *, checked_mul)),
+        }
     }
 }
 
 impl Div for Value {
-    type Output = Self;
+    type Output = Arithmetic;
 
-    fn div(self, other: Self) -> Self {
-        apply_operator!(self,other,/)
+    fn div(self, other: Self) -> Arithmetic {
+        match (&self, &other) {
+            (Value::Array(_), _) | (_, Value::Array(_)) => element_wise(self, other, Self::div),
+            _ => {
+                divisor(&self, &other)?;
+                Ok(apply_operator!(self, other, /, checked_div))
+            }
+        }
     }
 }
 
 impl Rem for Value {
-    type Output = Self;
+    type Output = Arithmetic;
 
-    fn rem(self, other: Self) -> Self {
-        apply_operator!(self,other,%)
+    fn rem(self, other: Self) -> Arithmetic {
+        match (&self, &other) {
+            (Value::Array(_), _) | (_, Value::Array(_)) => element_wise(self, other, Self::rem),
+            _ => {
+                divisor(&self, &other)?;
+                Ok(apply_operator!(self, other, %, checked_rem))
+            }
+        }
     }
 }
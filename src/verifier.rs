@@ -0,0 +1,784 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result};
+
+use crate::instruction::{Instruction, MAX_REGISTERS};
+use crate::process::{Config, Limits};
+use crate::program::{LineTable, Program};
+use crate::value::Type;
+
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub addr: usize,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.line {
+            Some(line) => write!(
+                f,
+                "{}: {:04} (line {}): {}",
+                severity, self.addr, line, self.message
+            ),
+            None => write!(f, "{}: {:04}: {}", severity, self.addr, self.message),
+        }
+    }
+}
+
+// Stack effect of an instruction: the number of values it needs on the stack, and the number of
+// values it leaves in place of them. `clear` is the only instruction not expressible this way.
+fn stack_effect(i: &Instruction) -> (usize, usize) {
+    match i {
+        Instruction::Noop => (0, 0),
+        Instruction::Push(_) => (0, 1),
+        Instruction::Pop => (1, 0),
+        Instruction::Dump => (0, 0),
+        Instruction::Clear => (0, 0),
+        Instruction::Dup => (1, 2),
+        Instruction::Swap => (2, 2),
+        Instruction::Over => (2, 3),
+        Instruction::Rot => (3, 3),
+        Instruction::MinusRot => (3, 3),
+        Instruction::Nip => (2, 1),
+        Instruction::Tuck => (2, 3),
+        Instruction::Pick(n) => (n.saturating_add(1), n.saturating_add(2)),
+        Instruction::Roll(n) => (n.saturating_add(1), n.saturating_add(1)),
+        Instruction::Drop(n) => (*n, 0),
+        Instruction::Depth => (0, 1),
+        Instruction::Assert(_) => (1, 1),
+        Instruction::AssertEq(_) => (2, 2),
+        Instruction::AssertNe(_) => (2, 2),
+        Instruction::AssertLt(_) => (2, 2),
+        Instruction::AssertType(_, _) => (1, 1),
+        Instruction::AssertDepth(_, _) => (0, 0),
+        Instruction::AssertApprox(_, _, _) => (1, 1),
+        Instruction::AssertUlps(_, _, _) => (1, 1),
+        Instruction::Add => (2, 1),
+        Instruction::Sub => (2, 1),
+        Instruction::Mul => (2, 1),
+        Instruction::Div => (2, 1),
+        Instruction::Mod => (2, 1),
+        Instruction::Load(_) => (0, 1),
+        Instruction::Store(_) => (1, 0),
+        Instruction::IsSet(_) => (0, 1),
+        Instruction::RegClear => (0, 0),
+        Instruction::Print => (1, 1),
+        Instruction::Prints => (1, 1),
+        Instruction::Printv => (1, 1),
+        Instruction::Printd => (1, 1),
+        Instruction::Putc => (1, 1),
+        Instruction::Printf(_, n) => (*n, 0),
+        Instruction::Concat => (2, 1),
+        Instruction::Len => (1, 1),
+        Instruction::CharAt => (2, 1),
+        Instruction::ToStr => (1, 1),
+        Instruction::Parse(_) => (1, 1),
+        Instruction::Alloc(_) => (0, 1),
+        Instruction::HLoad => (2, 1),
+        Instruction::HStore => (3, 0),
+        Instruction::Free => (1, 0),
+        Instruction::Addr(_) => (0, 1),
+        Instruction::AGet => (2, 1),
+        Instruction::ASet => (3, 1),
+        Instruction::ALen => (1, 1),
+        Instruction::APush => (2, 1),
+        Instruction::Eq
+        | Instruction::Ne
+        | Instruction::Lt
+        | Instruction::Le
+        | Instruction::Gt
+        | Instruction::Ge => (2, 1),
+        Instruction::Cast(_) => (1, 1),
+        Instruction::Exit(_) => (0, 0),
+        Instruction::Try(_) => (0, 0),
+        Instruction::EndTry => (0, 0),
+        Instruction::Throw => (1, 0),
+        Instruction::Call(_) => (0, 0),
+        Instruction::Ret => (0, 0),
+        Instruction::Enter(_) => (0, 0),
+        Instruction::Leave => (0, 0),
+        Instruction::LLoad(_) => (0, 1),
+        Instruction::LStore(_) => (1, 0),
+        Instruction::Native(_, n) => (*n, 1),
+    }
+}
+
+// Addresses the execution may continue at after an instruction, returns from calls aside (see
+// `Routines`)
+fn successors(addr: usize, i: &Instruction) -> Vec<usize> {
+    match i {
+        Instruction::Exit(_) | Instruction::Throw | Instruction::Ret => vec![],
+        Instruction::Call(target) => vec![*target],
+        _ => vec![addr + 1],
+    }
+}
+
+// Calls and returns of a program: the `ret` instructions reached from the start of the routine
+// each `call` calls, and the `call` instructions each `ret` may return to
+struct Routines {
+    returns: Vec<Vec<usize>>,
+    callers: Vec<Vec<usize>>,
+}
+
+impl Routines {
+    fn new(prog: &Program) -> Routines {
+        let mut returns = vec![Vec::new(); prog.len()];
+        let mut callers = vec![Vec::new(); prog.len()];
+        for (addr, i) in prog.iter().enumerate() {
+            if let Instruction::Call(target) = i {
+                returns[addr] = routine_returns(prog, *target);
+                for r in &returns[addr] {
+                    callers[*r].push(addr);
+                }
+            }
+        }
+        Routines { returns, callers }
+    }
+}
+
+// Returns reached from the start of a routine, the calls it makes being assumed to return
+fn routine_returns(prog: &Program, start: usize) -> Vec<usize> {
+    let mut seen = vec![false; prog.len()];
+    let mut worklist = vec![start];
+    let mut returns = Vec::new();
+    while let Some(addr) = worklist.pop() {
+        if addr >= prog.len() || seen[addr] {
+            continue;
+        }
+        seen[addr] = true;
+        match &prog[addr] {
+            Instruction::Ret => returns.push(addr),
+            Instruction::Call(_) => worklist.push(addr + 1),
+            Instruction::Try(handler) => worklist.extend([addr + 1, *handler]),
+            i => worklist.extend(successors(addr, i)),
+        }
+    }
+    returns
+}
+
+// Handler of a `try` block, and the state it starts with when the error is raised before an
+// instruction of the block, given the states before `try` and before that instruction. `catch`
+// only truncates the stack to its depth at `try`, so the values the block popped before the error
+// are not given back: the stack is the shallowest of the two, with the error code on top.
+fn handler(
+    prog: &Program,
+    addr: usize,
+    at_try: &State,
+    i: &Instruction,
+    before: &State,
+) -> Option<(usize, State)> {
+    match &prog[addr] {
+        Instruction::Try(target) => {
+            let raised = before.stack.len().saturating_sub(stack_effect(i).0);
+            let mut stack = vec![None; at_try.stack.len().min(raised)];
+            stack.push(None);
+            Some((
+                *target,
+                State {
+                    stack,
+                    registers: before.registers,
+                    frames: at_try.frames.clone(),
+                },
+            ))
+        }
+        _ => None,
+    }
+}
+
+// Addresses of the instructions whose errors a `try` block starting at the given address catches,
+// up to its `endtry`, including the routines it calls and the handlers of the blocks nested in it
+fn block(prog: &Program, start: usize) -> Vec<usize> {
+    let mut addresses = Vec::new();
+    // Addresses are visited along with the number of blocks nested in the block there
+    let mut seen = HashSet::new();
+    let mut worklist = vec![(start, 0)];
+    while let Some((addr, depth)) = worklist.pop() {
+        if addr >= prog.len() || depth > prog.len() || !seen.insert((addr, depth)) {
+            continue;
+        }
+        if depth == 0 {
+            addresses.push(addr);
+        }
+        match &prog[addr] {
+            Instruction::EndTry if depth == 0 => {}
+            Instruction::EndTry => worklist.push((addr + 1, depth - 1)),
+            Instruction::Try(handler) => {
+                worklist.extend([(addr + 1, depth + 1), (*handler, depth)]);
+            }
+            Instruction::Call(target) => worklist.extend([(addr + 1, depth), (*target, depth)]),
+            i => worklist.extend(successors(addr, i).into_iter().map(|next| (next, depth))),
+        }
+    }
+    addresses
+}
+
+// Abstract state of a process before executing an instruction, merged over all the paths reaching
+// it. A `None` type means the paths disagree on the type of the value.
+#[derive(Clone, PartialEq)]
+struct State {
+    // Only the values present on every path are kept, hence the depth is the minimal one, which is
+    // the one that matters for underflows
+    stack: Vec<Option<Type>>,
+    // `None` if the register is not stored on any path
+    registers: [Option<Option<Type>>; MAX_REGISTERS],
+    // Slot counts of the frames entered by the current routine, innermost last. As for the stack,
+    // only the innermost frames present on every path are kept, with their minimal slot count.
+    frames: Vec<usize>,
+}
+
+fn merge_type(a: Option<Type>, b: Option<Type>) -> Option<Type> {
+    match (a, b) {
+        (Some(a), Some(b)) if a == b => Some(a),
+        _ => None,
+    }
+}
+
+impl State {
+    fn merge(&self, other: &State) -> State {
+        let depth = self.stack.len().min(other.stack.len());
+        let stack = self.stack[self.stack.len() - depth..]
+            .iter()
+            .zip(&other.stack[other.stack.len() - depth..])
+            .map(|(a, b)| merge_type(*a, *b))
+            .collect();
+        let mut registers = self.registers;
+        for (r, other) in registers.iter_mut().zip(other.registers) {
+            *r = match (*r, other) {
+                (Some(a), Some(b)) => Some(merge_type(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+        let count = self.frames.len().min(other.frames.len());
+        let frames = self.frames[self.frames.len() - count..]
+            .iter()
+            .zip(&other.frames[other.frames.len() - count..])
+            .map(|(a, b)| *a.min(b))
+            .collect();
+        State {
+            stack,
+            registers,
+            frames,
+        }
+    }
+
+    fn pop(&mut self) -> Option<Type> {
+        self.stack.pop().flatten()
+    }
+
+    fn top(&self) -> Option<Type> {
+        self.stack.last().copied().flatten()
+    }
+
+    // Adds values of unknown type below the stack so that it holds at least n values, for the
+    // instructions reaching below its top
+    fn reserve(&mut self, n: usize) {
+        if self.stack.len() < n {
+            let missing = n - self.stack.len();
+            self.stack.splice(0..0, vec![None; missing]);
+        }
+    }
+}
+
+// Reserves the n + 1 values reached by a `pick` or `roll` of operand n. An operand deeper than a
+// stack may be under the limits is reported instead of building that many values, and the values
+// below the stack stay unknown.
+fn reserve_operand(
+    issues: &mut Vec<String>,
+    i: &Instruction,
+    n: usize,
+    state: &mut State,
+    limits: &Limits,
+) -> bool {
+    let max = limits.max_stack_depth;
+    if n < state.stack.len() || n < max {
+        state.reserve(n + 1);
+        return true;
+    }
+    issues.push(format!("{:?}: operand exceeds the stack limit of {} values", i, max));
+    false
+}
+
+// Reports a slot outside of the innermost frame of the current routine
+fn expect_slot(issues: &mut Vec<String>, i: &Instruction, slot: usize, state: &State) {
+    match state.frames.last() {
+        None => issues.push(format!("{:?}: no frame entered by the current routine", i)),
+        Some(n) if slot >= *n => issues.push(format!(
+            "{:?}: slot is outside the frame, which may have only {} slot(s)",
+            i, n
+        )),
+        _ => {}
+    }
+}
+
+// Reports a value whose inferred type is not the one an instruction expects
+fn expect_type(issues: &mut Vec<String>, i: &Instruction, t: Option<Type>, expected: Type) {
+    if let Some(t) = t {
+        if t != expected {
+            issues.push(format!("{:?}: value is inferred {}, not {}", i, t, expected));
+        }
+    }
+}
+
+// Reports a value whose inferred type is not an integer one, where an instruction expects one
+fn expect_integer(issues: &mut Vec<String>, i: &Instruction, t: Option<Type>, what: &str) {
+    if let Some(t) = t {
+        if t > Type::Int32 {
+            issues.push(format!("{:?}: {} is inferred {}, not an integer", i, what, t));
+        }
+    }
+}
+
+// Reports a value whose inferred type is neither an array nor a tuple, where an instruction
+// expects one
+fn expect_composite(issues: &mut Vec<String>, i: &Instruction, t: Option<Type>) {
+    if let Some(t) = t {
+        if t != Type::Array && t != Type::Tuple {
+            issues.push(format!("{:?}: value is inferred {}, not array or tuple", i, t));
+        }
+    }
+}
+
+// Applies an instruction to an abstract state, returning the problems it would raise. Missing
+// values are considered of unknown type, so that the analysis goes on as if they were there and
+// the code following an underflow is not reported too.
+fn step(state: &mut State, i: &Instruction, loose: bool, limits: &Limits) -> Vec<String> {
+    let mut issues = Vec::new();
+    let (needed, _) = stack_effect(i);
+    if state.stack.len() < needed {
+        issues.push(format!(
+            "stack underflow - {:?}: needs {} value(s), may have only {}",
+            i,
+            needed,
+            state.stack.len()
+        ));
+    }
+    match i {
+        Instruction::Noop
+        | Instruction::Dump
+        | Instruction::Printv
+        | Instruction::Printd
+        | Instruction::Exit(_)
+        | Instruction::Try(_)
+        | Instruction::EndTry
+        | Instruction::Ret => {}
+        Instruction::AssertEq(_)
+        | Instruction::AssertNe(_)
+        | Instruction::AssertLt(_)
+        | Instruction::AssertDepth(_, _)
+        | Instruction::AssertApprox(_, _, _)
+        | Instruction::AssertUlps(_, _, _) => {}
+        Instruction::Push(v) => state.stack.push(Some(v.get_type())),
+        Instruction::Pop => {
+            state.pop();
+        }
+        Instruction::Clear => state.stack.clear(),
+        Instruction::Dup => {
+            let t = state.pop();
+            state.stack.push(t);
+            state.stack.push(t);
+        }
+        Instruction::Swap => {
+            let (t1, t2) = (state.pop(), state.pop());
+            state.stack.push(t1);
+            state.stack.push(t2);
+        }
+        Instruction::Over | Instruction::Pick(_) => {
+            let n = match i {
+                Instruction::Pick(n) => *n,
+                _ => 1,
+            };
+            if !reserve_operand(&mut issues, i, n, state, limits) {
+                state.stack.push(None);
+                return issues;
+            }
+            let t = state.stack[state.stack.len() - 1 - n];
+            state.stack.push(t);
+        }
+        Instruction::Rot | Instruction::Roll(_) => {
+            let n = match i {
+                Instruction::Roll(n) => *n,
+                _ => 2,
+            };
+            if !reserve_operand(&mut issues, i, n, state, limits) {
+                state.stack.push(None);
+                return issues;
+            }
+            let t = state.stack.remove(state.stack.len() - 1 - n);
+            state.stack.push(t);
+        }
+        Instruction::MinusRot => {
+            state.reserve(3);
+            let t = state.stack.pop().flatten();
+            state.stack.insert(state.stack.len() - 2, t);
+        }
+        Instruction::Nip => {
+            state.reserve(2);
+            state.stack.remove(state.stack.len() - 2);
+        }
+        Instruction::Tuck => {
+            state.reserve(2);
+            let t = state.top();
+            state.stack.insert(state.stack.len() - 2, t);
+        }
+        Instruction::Drop(n) => {
+            let len = state.stack.len();
+            state.stack.truncate(len.saturating_sub(*n));
+        }
+        Instruction::Depth => state.stack.push(Some(Type::Int32)),
+        Instruction::Assert(v) => {
+            let t = state.pop();
+            if let Some(t) = t {
+                // In loose mode, values of different types may be equal
+                if !loose && t != v.get_type() {
+                    issues.push(format!(
+                        "assertion can never succeed: value is inferred {}, not {}",
+                        t,
+                        v.get_type()
+                    ));
+                }
+            }
+            state.stack.push(t);
+        }
+        Instruction::AssertType(t, _) => {
+            if let Some(top) = state.top() {
+                if top != *t {
+                    issues.push(format!(
+                        "assertion can never succeed: value is inferred {}, not {}",
+                        top, t
+                    ));
+                }
+            }
+        }
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod => {
+            let t = match (state.pop(), state.pop()) {
+                (Some(Type::String), _) | (_, Some(Type::String)) => {
+                    issues.push(format!("{:?}: arithmetic on a string", i));
+                    None
+                }
+                (Some(t), _) | (_, Some(t)) if t > Type::Array => {
+                    issues.push(format!("{:?}: arithmetic on a {}", i, t));
+                    None
+                }
+                (Some(t1), Some(t2)) => Some(t1.promote(t2)),
+                _ => None,
+            };
+            state.stack.push(t);
+        }
+        Instruction::Prints => {
+            expect_type(&mut issues, i, state.top(), Type::String);
+        }
+        Instruction::Concat => {
+            let (t1, t2) = (state.pop(), state.pop());
+            expect_type(&mut issues, i, t2, Type::String);
+            expect_type(&mut issues, i, t1, Type::String);
+            state.stack.push(Some(Type::String));
+        }
+        Instruction::Len => {
+            let t = state.pop();
+            expect_type(&mut issues, i, t, Type::String);
+            state.stack.push(Some(Type::Int32));
+        }
+        Instruction::CharAt => {
+            let (t1, t2) = (state.pop(), state.pop());
+            expect_integer(&mut issues, i, t1, "index");
+            expect_type(&mut issues, i, t2, Type::String);
+            state.stack.push(Some(Type::Char));
+        }
+        Instruction::ToStr => {
+            state.pop();
+            state.stack.push(Some(Type::String));
+        }
+        Instruction::Parse(t) => {
+            let top = state.pop();
+            expect_type(&mut issues, i, top, Type::String);
+            state.stack.push(Some(*t));
+        }
+        Instruction::Load(r) => match state.registers[*r] {
+            None => {
+                issues.push(format!("load: register is never stored: r{}", r));
+                state.stack.push(None);
+            }
+            Some(t) => state.stack.push(t),
+        },
+        Instruction::Store(r) => state.registers[*r] = Some(state.pop()),
+        Instruction::IsSet(_) => state.stack.push(Some(Type::Bool)),
+        Instruction::RegClear => state.registers = [None; MAX_REGISTERS],
+        Instruction::Print | Instruction::Putc => {
+            let t = state.top();
+            if let Some(t) = t {
+                if t != Type::Int8 && t != Type::Char {
+                    issues.push(format!("{:?}: value is inferred {}, not int8 or char", i, t));
+                }
+            }
+        }
+        Instruction::Eq
+        | Instruction::Ne
+        | Instruction::Lt
+        | Instruction::Le
+        | Instruction::Gt
+        | Instruction::Ge => {
+            state.pop();
+            state.pop();
+            state.stack.push(Some(Type::Bool));
+        }
+        Instruction::Cast(t) => {
+            state.pop();
+            state.stack.push(Some(*t));
+        }
+        Instruction::Alloc(_) => state.stack.push(Some(Type::Int32)),
+        Instruction::HLoad => {
+            let (t1, t2) = (state.pop(), state.pop());
+            expect_integer(&mut issues, i, t1, "index");
+            expect_integer(&mut issues, i, t2, "handle");
+            state.stack.push(None);
+        }
+        Instruction::HStore => {
+            let (_, t1, t2) = (state.pop(), state.pop(), state.pop());
+            expect_integer(&mut issues, i, t1, "index");
+            expect_integer(&mut issues, i, t2, "handle");
+        }
+        Instruction::Free => {
+            let t = state.pop();
+            expect_integer(&mut issues, i, t, "handle");
+        }
+        Instruction::Addr(_) => state.stack.push(Some(Type::Int32)),
+        Instruction::AGet => {
+            let (t1, t2) = (state.pop(), state.pop());
+            expect_integer(&mut issues, i, t1, "index");
+            expect_composite(&mut issues, i, t2);
+            state.stack.push(None);
+        }
+        Instruction::ASet => {
+            let (_, t1, t2) = (state.pop(), state.pop(), state.pop());
+            expect_integer(&mut issues, i, t1, "index");
+            expect_composite(&mut issues, i, t2);
+            state.stack.push(t2);
+        }
+        Instruction::ALen => {
+            let t = state.pop();
+            expect_composite(&mut issues, i, t);
+            state.stack.push(Some(Type::Int32));
+        }
+        Instruction::APush => {
+            let (_, t) = (state.pop(), state.pop());
+            expect_type(&mut issues, i, t, Type::Array);
+            state.stack.push(Some(Type::Array));
+        }
+        Instruction::Printf(_, n) => {
+            let len = state.stack.len();
+            state.stack.truncate(len.saturating_sub(*n));
+        }
+        Instruction::Throw => {
+            state.pop();
+        }
+        // The called routine has no frame, the caller's ones being back when it returns
+        Instruction::Call(_) => state.frames.clear(),
+        Instruction::Enter(n) => state.frames.push(*n),
+        Instruction::Leave => {
+            if state.frames.pop().is_none() {
+                issues.push(String::from("leave: no frame entered by the current routine"));
+            }
+        }
+        Instruction::LLoad(slot) => {
+            expect_slot(&mut issues, i, *slot, state);
+            state.stack.push(None);
+        }
+        Instruction::LStore(slot) => {
+            expect_slot(&mut issues, i, *slot, state);
+            state.pop();
+        }
+        Instruction::Native(_, n) => {
+            let len = state.stack.len();
+            state.stack.truncate(len.saturating_sub(*n));
+            state.stack.push(None);
+        }
+    }
+    issues
+}
+
+// Abstract interpretation of the program along every control-flow path, until the state before
+// each instruction is stable. Unreachable instructions have no state.
+fn analyze(prog: &Program, limits: &Limits) -> Vec<Option<State>> {
+    let routines = Routines::new(prog);
+    let mut states: Vec<Option<State>> = vec![None; prog.len()];
+    // Blocks guarding each instruction, and instructions guarded by each block
+    let mut blocks = vec![Vec::new(); prog.len()];
+    let mut guarded = HashMap::new();
+    for (t, i) in prog.iter().enumerate() {
+        if let Instruction::Try(_) = i {
+            let addresses = block(prog, t + 1);
+            for &a in &addresses {
+                blocks[a].push(t);
+            }
+            guarded.insert(t, addresses);
+        }
+    }
+    let mut worklist = Vec::new();
+    if !prog.is_empty() {
+        states[0] = Some(State {
+            stack: Vec::new(),
+            registers: [None; MAX_REGISTERS],
+            frames: Vec::new(),
+        });
+        worklist.push(0);
+    }
+    while let Some(addr) = worklist.pop() {
+        let before = match &states[addr] {
+            Some(state) => state.clone(),
+            None => continue,
+        };
+        let mut state = before.clone();
+        step(&mut state, &prog[addr], false, limits);
+        let mut edges: Vec<(usize, State)> = successors(addr, &prog[addr])
+            .into_iter()
+            .map(|next| (next, state.clone()))
+            .collect();
+        // An error before an instruction of a block jumps to the handler of the block, and so does
+        // the `try` itself for an empty block
+        edges.extend(handler(prog, addr, &before, &Instruction::Noop, &before));
+        for &t in &blocks[addr] {
+            if let Some(at_try) = &states[t] {
+                edges.extend(handler(prog, t, at_try, &prog[addr], &before));
+            }
+        }
+        for &a in guarded.get(&addr).into_iter().flatten() {
+            if let Some(state) = &states[a] {
+                edges.extend(handler(prog, addr, &before, &prog[a], state));
+            }
+        }
+        // A call continues after it once the returns of its routine are reached: the values the
+        // routine starts with on every path are replaced by the ones it returns, the values below
+        // and the frames being the caller's
+        let returns = routines.returns[addr].iter().map(|r| (addr, *r));
+        let callers = routines.callers[addr].iter().map(|c| (*c, addr));
+        for (call, ret) in returns.chain(callers) {
+            let entry = match &prog[call] {
+                Instruction::Call(target) => states.get(*target).and_then(|s| s.as_ref()),
+                _ => None,
+            };
+            if let (Some(caller), Some(entry), Some(callee)) = (&states[call], entry, &states[ret])
+            {
+                let kept = caller.stack.len().saturating_sub(entry.stack.len());
+                let mut stack = caller.stack[..kept].to_vec();
+                stack.extend(&callee.stack);
+                let state = State {
+                    stack,
+                    registers: callee.registers,
+                    frames: caller.frames.clone(),
+                };
+                edges.push((call + 1, state));
+            }
+        }
+        for (next, state) in edges {
+            if next >= prog.len() {
+                continue;
+            }
+            let merged = match &states[next] {
+                Some(s) => s.merge(&state),
+                None => state.clone(),
+            };
+            if states[next].as_ref() != Some(&merged) {
+                states[next] = Some(merged);
+                worklist.push(next);
+            }
+        }
+    }
+    states
+}
+
+// Minimal stack depth before each instruction over all the paths reaching it, `None` if the
+// instruction is unreachable
+pub fn stack_depths(prog: &Program) -> Vec<Option<usize>> {
+    analyze(prog, &Limits::default())
+        .iter()
+        .map(|s| s.as_ref().map(|s| s.stack.len()))
+        .collect()
+}
+
+// Problems are reported from the final states of the analysis only, under the limits and in the
+// assertion mode (see `Config::loose`) of the configuration the program would run with
+pub fn verify(prog: &Program, lines: Option<&LineTable>, config: &Config) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut report = |severity, addr: usize, message| {
+        diagnostics.push(Diagnostic {
+            severity,
+            addr,
+            line: lines.and_then(|l| l.get(addr).copied()),
+            message,
+        })
+    };
+
+    let states = analyze(prog, &config.limits);
+    let routines = Routines::new(prog);
+    let mut unreachable_start = None;
+    for (addr, instruction) in prog.iter().enumerate() {
+        match (&states[addr], unreachable_start) {
+            (None, None) => unreachable_start = Some(addr),
+            (Some(_), Some(start)) => {
+                report(
+                    Severity::Warning,
+                    start,
+                    format!("unreachable code ({} instruction(s))", addr - start),
+                );
+                unreachable_start = None;
+            }
+            _ => {}
+        }
+        if let Some(state) = &states[addr] {
+            for issue in step(&mut state.clone(), instruction, config.loose, &config.limits) {
+                report(Severity::Error, addr, issue);
+            }
+            match instruction {
+                Instruction::Try(target) if *target >= prog.len() => report(
+                    Severity::Error,
+                    addr,
+                    format!("try: handler address {} is out of the program", target),
+                ),
+                Instruction::Call(target) if *target >= prog.len() => report(
+                    Severity::Error,
+                    addr,
+                    format!("call: address {} is out of the program", target),
+                ),
+                _ => {}
+            }
+            // A call continues after it if its routine returns
+            let returns = !routines.returns[addr].is_empty() && addr + 1 == prog.len();
+            if successors(addr, instruction).contains(&prog.len()) || returns {
+                report(
+                    Severity::Error,
+                    addr,
+                    String::from("reaches end of program instructions without exiting"),
+                );
+            }
+        }
+    }
+    if let Some(start) = unreachable_start {
+        report(
+            Severity::Warning,
+            start,
+            format!("unreachable code ({} instruction(s))", prog.len() - start),
+        );
+    }
+    if prog.is_empty() {
+        report(
+            Severity::Error,
+            0,
+            String::from("reaches end of program instructions without exiting"),
+        );
+    }
+    diagnostics
+}
@@ -1,43 +1,78 @@
 use std::collections::hash_map::HashMap;
 use std::process::ExitCode;
 use std::result;
+use std::time::Instant;
 
-use crate::process::Process;
-use crate::program::Program;
+use crate::native::{Native, Natives};
+use crate::process::{Config, Process};
+use crate::program::{Data, Program};
+use crate::value::Value;
 
 const VM_RUN_CYCLES: usize = 7;
 
 pub struct VM {
     last_pid: usize,
+    config: Config,
     processes: HashMap<usize, Process>,
+    natives: Natives,
 }
 
 impl VM {
-    pub fn new() -> VM {
+    pub fn new(config: Config) -> VM {
         VM {
             last_pid: 0,
+            config,
             processes: HashMap::new(),
+            natives: Natives::default(),
         }
     }
 
-    pub fn load_program(&mut self, p: Program) -> usize {
-        self.last_pid = self.last_pid + 1;
-        self.processes.insert(self.last_pid, Process::new(p));
+    // Registers a native function, called by `native <name> <n>` (see `Natives::register`)
+    pub fn register<F>(&mut self, name: &str, f: F) -> &mut Native
+    where
+        F: Fn(&[Value]) -> result::Result<Value, String> + 'static,
+    {
+        self.natives.register(name, f)
+    }
+
+    // Native functions registered, for the optimizer to call the pure ones
+    pub fn natives(&self) -> &Natives {
+        &self.natives
+    }
+
+    pub fn load_program(&mut self, p: Program, data: Data) -> usize {
+        self.last_pid += 1;
+        self.processes
+            .insert(self.last_pid, Process::new(p, data, self.config.clone()));
         self.last_pid
     }
 
     pub fn run_process(&mut self, pid: usize) -> result::Result<ExitCode, String> {
+        let start = Instant::now();
         match self.processes.get_mut(&pid) {
             None => Err(format!("process ID does not exists: {}", pid)),
             Some(p) => loop {
-                match p.run(VM_RUN_CYCLES) {
+                if let Some(timeout) = self.config.limits.timeout {
+                    if start.elapsed() > timeout {
+                        self.processes.remove(&pid);
+                        return Err(format!("limit exceeded: timeout after {:?}", timeout));
+                    }
+                }
+                match p.run(VM_RUN_CYCLES, &self.natives) {
                     Ok(r) => {
-                        if r == false {
+                        if !r {
+                            report_leaks(p);
+                            let code = p.exit_code();
+                            let failures = report_failures(p, None);
                             self.processes.remove(&pid);
-                            return Ok(ExitCode::SUCCESS);
+                            return match failures {
+                                None => Ok(ExitCode::from(code)),
+                                Some(e) => Err(e),
+                            };
                         }
                     }
                     Err(e) => {
+                        let e = report_failures(p, Some(e.to_string())).unwrap_or_default();
                         self.processes.remove(&pid);
                         return Err(e);
                     }
@@ -46,3 +81,31 @@ impl VM {
         }
     }
 }
+
+// Heap blocks not freed when a process exits are reported, without failing it
+fn report_leaks(p: &Process) {
+    let leaks = p.leaked_blocks();
+    if !leaks.is_empty() {
+        let handles: Vec<String> = leaks.iter().map(|n| n.to_string()).collect();
+        eprintln!(
+            "warning: {} heap block(s) not freed at exit (handles {})",
+            leaks.len(),
+            handles.join(", ")
+        );
+    }
+}
+
+// Assertion failures recorded by a process in check mode, reported along with the error that
+// stopped it, if any
+fn report_failures(p: &Process, error: Option<String>) -> Option<String> {
+    let failures = p.failed_assertions();
+    if failures.is_empty() {
+        return error;
+    }
+    let mut report = failures.join("\n");
+    report.push_str(&format!("\n{} assertion(s) failed", failures.len()));
+    if let Some(e) = error {
+        report.push_str(&format!("\n{}", e));
+    }
+    Some(report)
+}
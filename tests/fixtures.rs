@@ -0,0 +1,64 @@
+use std::fs;
+use std::process::{Command, Output};
+
+const DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
+
+fn abstractvm(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_abstractvm"))
+        .args(args)
+        .output()
+        .expect("couldn't run abstractvm")
+}
+
+// Runs every `.avm` fixture of this directory through `abstractvm test`, which checks their
+// results against the expected ones (see `tests/*.avm.out` and inline `; expect:` comments)
+#[test]
+fn fixtures() {
+    let output = abstractvm(&["test", DIR]);
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "some fixtures failed");
+}
+
+// Runs the fixtures of `tests/optimize` as the other ones, and checks that they behave the same
+// once optimized by `-O`
+#[test]
+fn optimized() {
+    let dir = format!("{}/optimize", DIR);
+    let output = abstractvm(&["test", &dir]);
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    assert!(output.status.success(), "some fixtures failed");
+    for entry in fs::read_dir(&dir).expect("couldn't read the fixtures") {
+        let path = entry.expect("couldn't read the fixtures").path();
+        if path.extension().is_none_or(|e| e != "avm") {
+            continue;
+        }
+        let path = path.to_str().unwrap();
+        let (plain, optimized) = (abstractvm(&[path]), abstractvm(&["-O", path]));
+        assert_eq!(plain.stdout, optimized.stdout, "stdout differs with -O: {}", path);
+        assert_eq!(plain.stderr, optimized.stderr, "stderr differs with -O: {}", path);
+        assert_eq!(plain.status, optimized.status, "exit code differs with -O: {}", path);
+    }
+}
+
+// Blesses a program without inline expectations, whose strings look like some, and checks it
+// then passes against the sidecar files written
+#[test]
+fn bless() {
+    let dir = std::env::temp_dir().join(format!("abstractvm-bless-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("couldn't create the test directory");
+    let program = dir.join("bless.avm");
+    let source = "push string(\"; expect: nothing\")\nprints\npop\npush int8(0)\ndiv ; fails\n";
+    fs::write(&program, source).expect("couldn't write the program");
+
+    let dir_name = dir.to_str().unwrap();
+    let blessed = abstractvm(&["test", "--bless", dir_name]);
+    assert!(blessed.status.success(), "blessing failed");
+    let read = |extension: &str| fs::read_to_string(dir.join(format!("bless.avm.{}", extension)));
+    assert_eq!(read("out").unwrap(), "; expect: nothing\n");
+    assert_eq!(read("err").unwrap(), "stack underflow - div\n");
+    assert_eq!(read("code").unwrap(), "84\n");
+    let tested = abstractvm(&["test", dir_name]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(tested.status.success(), "blessed program failed");
+}